@@ -58,9 +58,10 @@ cargo build --release
 popd
 
 # Remove any previously-generated data
-rm -rf web/geojson border-explorer.db
+rm -rf web/geojson border-explorer.db border-explorer.db-wal border-explorer.db-shm
 # Process the dump
-"$SCRIPT_DIR/target/release/border-explorer" border-explorer.db "$DUMP_FILE"
+"$SCRIPT_DIR/target/release/border-explorer" --db border-explorer.db ingest "$DUMP_FILE"
+"$SCRIPT_DIR/target/release/border-explorer" --db border-explorer.db generate
 
 # Upload to github remote gh-pages branch
 if [ ! -d gh-pages ]; then
@@ -0,0 +1,711 @@
+// Parallel bzip2 decompression
+//
+// A bzip2 stream is a sequence of independently compressed blocks (up to 900kB of input each),
+// every one of them starting with a 48-bit magic number. Blocks are not byte-aligned, but once
+// found, each of them can be re-wrapped in its own standalone single-block stream and decoded on
+// any thread. This is what lbzip2 does, and it's the only way to make decompression of a
+// 100GB dump not be the bottleneck.
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use bzip2::read::BzDecoder;
+
+use super::parallel::{self, Feeder, Ordered};
+
+const BLOCK_MAGIC: u64 = 0x3141_5926_5359;
+const END_OF_STREAM_MAGIC: u64 = 0x1772_4538_5090;
+const MAGIC_MASK: u64 = (1 << 48) - 1;
+/* A false positive magic inside compressed data has a 2^-48 chance per bit; only try to recover
+ * from a couple of them in a row, more likely means real corruption */
+const MAX_MERGED_BLOCKS: usize = 3;
+
+struct Block {
+    /// Position of the first bit of this block in the compressed input
+    start_bit: u64,
+    /// Block bits, shifted to start on a byte boundary
+    data: Vec<u8>,
+    nbits: u64,
+}
+impl Block {
+    fn byte_offset(&self) -> u64 {
+        self.start_bit / 8
+    }
+    fn end_bit(&self) -> u64 {
+        self.start_bit + self.nbits
+    }
+}
+
+/// Start of a block, where decompression can restart
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct BlockStart {
+    /// Position of its first bit in the compressed input
+    pub(crate) bit: u64,
+    /// Decompressed bytes before it
+    pub(crate) offset: u64,
+}
+
+/// Starts of the blocks decoded so far, shared with the reader of the decompressed content
+#[derive(Clone, Default)]
+pub(crate) struct BlockStarts(Arc<Mutex<VecDeque<BlockStart>>>);
+impl BlockStarts {
+    fn push(&self, start: BlockStart) {
+        self.0
+            .lock()
+            .expect("poisoned block starts")
+            .push_back(start);
+    }
+
+    /// The last block starting at or before this decompressed offset; the previous ones are
+    /// forgotten, offsets must not decrease
+    pub(crate) fn before(&self, offset: u64) -> Option<BlockStart> {
+        let mut starts = self.0.lock().expect("poisoned block starts");
+        while starts.get(1).is_some_and(|next| next.offset <= offset) {
+            starts.pop_front();
+        }
+        starts
+            .front()
+            .filter(|start| start.offset <= offset)
+            .copied()
+    }
+}
+
+struct Decoded {
+    block: Block,
+    result: io::Result<Vec<u8>>,
+}
+
+/// Reader decompressing a bzip2 (possibly multi-stream) input, using one thread per core
+pub(crate) struct MultiBzDecoder {
+    decoded: Ordered<Decoded>,
+    current: Vec<u8>,
+    pos: usize,
+    scanner: Option<thread::JoinHandle<io::Result<()>>>,
+    /// Recorded when checkpoints need them
+    block_starts: Option<BlockStarts>,
+    /// Decompressed bytes before the current block
+    offset: u64,
+    /// Block expected first, when resuming
+    resume_at: Option<u64>,
+}
+
+impl MultiBzDecoder {
+    pub(crate) fn new<R: Read + Send + 'static>(input: R) -> Self {
+        Self::start(input, None, None)
+    }
+
+    /// Decoder recording the start of each block, to restart from one of them later. When
+    /// resuming from such a block, the input must be positioned at the byte it starts in.
+    pub(crate) fn resumable<R: Read + Send + 'static>(
+        input: R,
+        from: Option<BlockStart>,
+    ) -> (Self, BlockStarts) {
+        let block_starts = BlockStarts::default();
+        let decoder = Self::start(input, from, Some(block_starts.clone()));
+        (decoder, block_starts)
+    }
+
+    fn start<R: Read + Send + 'static>(
+        input: R,
+        from: Option<BlockStart>,
+        block_starts: Option<BlockStarts>,
+    ) -> Self {
+        let threads = thread::available_parallelism().map_or(4, |n| n.get());
+        // Blocks scanned but not read yet, including those decoded ahead of a slow one
+        let (blocks, workers, decoded) = parallel::ordered(threads * 2);
+        workers.start(threads, |worker| {
+            thread::spawn(move || {
+                worker.run(|block: Block| Decoded {
+                    result: decode(&block),
+                    block,
+                })
+            });
+        });
+        let resume_at = from.map(|from| from.bit);
+        let scanner = thread::spawn(move || scan(input, blocks, resume_at.map(|bit| bit / 8)));
+        Self {
+            decoded,
+            current: vec![],
+            pos: 0,
+            scanner: Some(scanner),
+            block_starts,
+            offset: from.map_or(0, |from| from.offset),
+            resume_at,
+        }
+    }
+
+    /// Wait for the next decoded block; None at end of input
+    fn fetch(&mut self) -> io::Result<Option<Decoded>> {
+        if let Some(decoded) = self.decoded.next().map_err(io::Error::other)? {
+            return Ok(Some(decoded));
+        }
+        // All workers are gone, the scanner has finished
+        if let Some(scanner) = self.scanner.take() {
+            scanner
+                .join()
+                .map_err(|_| io::Error::other("bzip2 scanner thread panicked"))??;
+        }
+        Ok(None)
+    }
+
+    fn next_block(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let Some(mut decoded) = self.fetch()? else {
+            return Ok(None);
+        };
+        if let Some(bit) = self.resume_at.take()
+            && decoded.block.start_bit != bit
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "no bzip2 block to resume from at compressed byte offset {}",
+                    bit / 8
+                ),
+            ));
+        }
+        let mut merged = 0;
+        loop {
+            let err = match decoded.result {
+                Ok(data) => {
+                    if let Some(ref starts) = self.block_starts {
+                        starts.push(BlockStart {
+                            bit: decoded.block.start_bit,
+                            offset: self.offset,
+                        });
+                    }
+                    self.offset += data.len() as u64;
+                    return Ok(Some(data));
+                }
+                Err(e) => e,
+            };
+            // Maybe the block was cut short by a magic number appearing by chance in the
+            // compressed data: retry with the following block glued back to it
+            let following = if merged < MAX_MERGED_BLOCKS {
+                self.fetch()?
+            } else {
+                None
+            };
+            let following = match following {
+                Some(f) if f.block.start_bit == decoded.block.end_bit() => f,
+                Some(f) => {
+                    self.decoded.put_back(f);
+                    return Err(block_error(&decoded.block, err));
+                }
+                None => return Err(block_error(&decoded.block, err)),
+            };
+            merged += 1;
+            let block = concat(decoded.block, following.block);
+            decoded = Decoded {
+                result: decode(&block),
+                block,
+            };
+        }
+    }
+}
+
+impl Read for MultiBzDecoder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.current.len() {
+            match self.next_block()? {
+                Some(data) => {
+                    self.current = data;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.current.len() - self.pos);
+        buf[..n].copy_from_slice(&self.current[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn block_error(block: &Block, e: io::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "corrupted bzip2 block at compressed byte offset {}: {e}",
+            block.byte_offset()
+        ),
+    )
+}
+
+/// Blocks of the compressed input, cut as their boundaries are found
+struct Cutter {
+    blocks: Feeder<Block>,
+    /// Raw bytes since the start of the current block; pending[0] is at byte offset pending_base
+    pending: Vec<u8>,
+    pending_base: u64,
+    block_start: Option<u64>,
+    found: usize,
+}
+impl Cutter {
+    /// End the current block, if any, at a magic starting at bit magic_start, and start a new
+    /// one there if it's a block magic; false when the reader is gone
+    fn cut(&mut self, magic_start: u64, block: bool) -> bool {
+        if let Some(start) = self.block_start {
+            let nbits = magic_start - start;
+            let data = extract_bits(&self.pending, start - self.pending_base * 8, nbits);
+            self.found += 1;
+            if !self.blocks.send(Block {
+                start_bit: start,
+                data,
+                nbits,
+            }) {
+                return false;
+            }
+        }
+        self.block_start = block.then_some(magic_start);
+        let keep_from = magic_start / 8;
+        self.pending
+            .drain(..(keep_from - self.pending_base) as usize);
+        self.pending_base = keep_from;
+        true
+    }
+
+    /// Whether the bytes at byte offset `at` are the header of a bzip2 stream
+    fn stream_header_at(&self, at: u64) -> bool {
+        let header = &self.pending[(at - self.pending_base) as usize..];
+        header.len() >= 4 && &header[..3] == b"BZh" && (b'1'..=b'9').contains(&header[3])
+    }
+}
+
+/// Byte offset following the stream CRC and padding, for an end-of-stream magic at bit `start`
+fn stream_end(start: u64) -> u64 {
+    (start + 48 + 32).div_ceil(8)
+}
+
+/// Find block boundaries in the compressed input, and send them to the workers in order; the
+/// input starts at byte offset `resume_at` when given, inside a stream
+fn scan<R: Read>(mut input: R, blocks: Feeder<Block>, resume_at: Option<u64>) -> io::Result<()> {
+    let mut buf = vec![0; 1024 * 1024];
+    let mut len = 0;
+    if resume_at.is_none() {
+        let mut header = [0; 4];
+        input.read_exact(&mut header)?;
+        if &header[..3] != b"BZh" || !(b'1'..=b'9').contains(&header[3]) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a bzip2 stream",
+            ));
+        }
+        len = header.len();
+        buf[..len].copy_from_slice(&header);
+    }
+    let mut cutter = Cutter {
+        blocks,
+        pending: Vec::with_capacity(1024 * 1024),
+        pending_base: resume_at.unwrap_or(0),
+        block_start: None,
+        found: 0,
+    };
+    /* End-of-stream magics found after a block, that could also be block data by chance: only a
+     * real one is followed by the stream CRC, padding, then the next stream header or the end of
+     * input */
+    let mut stream_ends: Vec<u64> = vec![];
+    let mut window: u64 = 0;
+    let mut offset: u64 = resume_at.unwrap_or(0);
+    let first_bit = offset * 8;
+    loop {
+        for &byte in &buf[..len] {
+            cutter.pending.push(byte);
+            window = (window << 8) | u64::from(byte);
+            offset += 1;
+            while let Some(&end) = stream_ends.first()
+                && stream_end(end) + 4 <= offset
+            {
+                if cutter.stream_header_at(stream_end(end)) {
+                    stream_ends.clear();
+                    if !cutter.cut(end, false) {
+                        return Ok(());
+                    }
+                } else {
+                    stream_ends.remove(0);
+                }
+            }
+            // Check all 8 possible alignments of a magic ending in this byte, earliest first
+            for shift in (0..8).rev() {
+                let candidate = (window >> shift) & MAGIC_MASK;
+                if candidate != BLOCK_MAGIC && candidate != END_OF_STREAM_MAGIC {
+                    continue;
+                }
+                // Not when its first bits would be before the start of the input
+                let Some(magic_start) = (offset * 8)
+                    .checked_sub(48 + shift)
+                    .filter(|&start| start >= first_bit)
+                else {
+                    continue;
+                };
+                if candidate == END_OF_STREAM_MAGIC && cutter.block_start.is_some() {
+                    stream_ends.push(magic_start);
+                    continue;
+                }
+                // Blocks never follow the end of a stream before the next stream header
+                stream_ends.clear();
+                if !cutter.cut(magic_start, candidate == BLOCK_MAGIC) {
+                    // Reader is gone, no need to continue
+                    return Ok(());
+                }
+            }
+            // Outside of blocks (stream headers and trailers), there is nothing worth keeping
+            if cutter.block_start.is_none() && cutter.pending.len() > 16 {
+                let drop = cutter.pending.len() - 8;
+                cutter.pending.drain(..drop);
+                cutter.pending_base += drop as u64;
+            }
+        }
+        len = loop {
+            match input.read(&mut buf) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!("failed to read compressed input at byte offset {offset}: {e}"),
+                    ));
+                }
+            }
+        };
+        if len == 0 {
+            break;
+        }
+    }
+    // The last stream ends with the input
+    if let Some(&end) = stream_ends.iter().find(|&&end| stream_end(end) == offset)
+        && !cutter.cut(end, false)
+    {
+        return Ok(());
+    }
+    if let Some(start) = cutter.block_start {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!(
+                "truncated bzip2 stream: block at compressed byte offset {} has no end",
+                start / 8
+            ),
+        ));
+    }
+    if cutter.found == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no bzip2 block found in input",
+        ));
+    }
+    Ok(())
+}
+
+/// Copy nbits from src, starting at bit first_bit, into a byte-aligned buffer
+fn extract_bits(src: &[u8], first_bit: u64, nbits: u64) -> Vec<u8> {
+    let first_byte = (first_bit / 8) as usize;
+    let shift = (first_bit % 8) as u32;
+    let len = nbits.div_ceil(8) as usize;
+    let mut out: Vec<u8> = if shift == 0 {
+        src[first_byte..first_byte + len].to_vec()
+    } else {
+        (0..len)
+            .map(|i| {
+                let hi = src[first_byte + i] << shift;
+                let lo = src.get(first_byte + i + 1).map_or(0, |b| b >> (8 - shift));
+                hi | lo
+            })
+            .collect()
+    };
+    let trailing = (nbits % 8) as u32;
+    if trailing != 0 {
+        *out.last_mut().expect("non-empty block") &= 0xff << (8 - trailing);
+    }
+    out
+}
+
+struct BitWriter {
+    buf: Vec<u8>,
+    /// Number of bits used in the last byte of buf, 8 when aligned
+    used: u32,
+}
+impl BitWriter {
+    fn with_capacity(n: usize) -> Self {
+        Self {
+            buf: Vec::with_capacity(n),
+            used: 8,
+        }
+    }
+    fn write(&mut self, value: u64, nbits: u32) {
+        for i in (0..nbits).rev() {
+            if self.used == 8 {
+                self.buf.push(0);
+                self.used = 0;
+            }
+            let bit = ((value >> i) & 1) as u8;
+            *self.buf.last_mut().expect("just pushed") |= bit << (7 - self.used);
+            self.used += 1;
+        }
+    }
+    fn write_bits(&mut self, data: &[u8], nbits: u64) {
+        let full = (nbits / 8) as usize;
+        if self.used == 8 {
+            self.buf.extend_from_slice(&data[..full]);
+        } else {
+            data[..full]
+                .iter()
+                .for_each(|byte| self.write(u64::from(*byte), 8));
+        }
+        let trailing = (nbits % 8) as u32;
+        if trailing != 0 {
+            self.write(u64::from(data[full] >> (8 - trailing)), trailing);
+        }
+    }
+    fn bit_len(&self) -> u64 {
+        (self.buf.len() as u64 * 8) - u64::from(8 - self.used)
+    }
+}
+
+fn concat(a: Block, b: Block) -> Block {
+    let mut w = BitWriter::with_capacity(a.data.len() + b.data.len());
+    w.write_bits(&a.data, a.nbits);
+    w.write_bits(&b.data, b.nbits);
+    Block {
+        start_bit: a.start_bit,
+        nbits: w.bit_len(),
+        data: w.buf,
+    }
+}
+
+/// Decode a single block, by wrapping it in its own bzip2 stream
+fn decode(block: &Block) -> io::Result<Vec<u8>> {
+    // Block magic is followed by the block CRC; for a single-block stream, the combined stream CRC
+    // is the same
+    let crc = block
+        .data
+        .get(6..10)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "block too short"))?;
+    let crc = u32::from_be_bytes(crc.try_into().expect("4 bytes"));
+    let mut w = BitWriter::with_capacity(block.data.len() + 16);
+    // Biggest block size, since we don't track which stream header a block comes from
+    w.write_bits(b"BZh9", 32);
+    w.write_bits(&block.data, block.nbits);
+    w.write(END_OF_STREAM_MAGIC, 48);
+    w.write(u64::from(crc), 32);
+
+    let mut out = Vec::with_capacity(block.data.len() * 8);
+    BzDecoder::new(&w.buf[..]).read_to_end(&mut out)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bzip2::Compression;
+    use bzip2::write::BzEncoder;
+    use std::io::Write;
+
+    /// Text compressing to several blocks at the smallest block size, without long runs
+    fn sample(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        let mut out = Vec::with_capacity(len);
+        while out.len() < len {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            write!(out, "Q{} ", state >> 40).expect("write to vec");
+        }
+        out.truncate(len);
+        out
+    }
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut encoder = BzEncoder::new(vec![], Compression::new(1));
+        encoder.write_all(data).expect("write to vec");
+        encoder.finish().expect("write to vec")
+    }
+
+    fn decompress(compressed: Vec<u8>) -> io::Result<Vec<u8>> {
+        let mut out = vec![];
+        MultiBzDecoder::new(io::Cursor::new(compressed)).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    fn reference(compressed: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        bzip2::read::MultiBzDecoder::new(compressed)
+            .read_to_end(&mut out)
+            .expect("valid bzip2");
+        out
+    }
+
+    /// Blocks as found by the scanner, in order
+    fn blocks(compressed: Vec<u8>) -> Vec<Block> {
+        let (feeder, workers, mut ordered) = parallel::ordered(1024);
+        workers.start(1, |worker| {
+            thread::spawn(move || worker.run(|block: Block| block));
+        });
+        scan(io::Cursor::new(compressed), feeder, None).expect("valid bzip2");
+        std::iter::from_fn(|| ordered.next().expect("no lost block")).collect()
+    }
+
+    #[test]
+    fn multi_block() {
+        let data = sample(1_000_000, 1);
+        let compressed = compress(&data);
+        assert!(blocks(compressed.clone()).len() > 3);
+        let decoded = decompress(compressed.clone()).expect("valid bzip2");
+        assert_eq!(decoded, reference(&compressed));
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn multi_stream() {
+        let mut compressed = compress(&sample(300_000, 2));
+        compressed.extend(compress(&sample(50_000, 3)));
+        compressed.extend(compress(&sample(250_000, 4)));
+        assert_eq!(
+            decompress(compressed.clone()).expect("valid bzip2"),
+            reference(&compressed)
+        );
+    }
+
+    #[test]
+    fn bits_at_every_shift() {
+        let data = [0xa5, 0x3c, 0xff, 0x01, 0x80, 0x7e];
+        for nbits in [1, 8, 13, 47, 48] {
+            let mut expected = data[..(nbits as usize).div_ceil(8)].to_vec();
+            if nbits % 8 != 0 {
+                *expected.last_mut().expect("non-empty") &= 0xff << (8 - nbits % 8);
+            }
+            for shift in 0..8 {
+                let mut w = BitWriter::with_capacity(8);
+                w.write(0b1011_0110 >> (8 - shift), shift as u32);
+                w.write_bits(&data, nbits);
+                assert_eq!(w.bit_len(), shift + nbits);
+                assert_eq!(extract_bits(&w.buf, shift, nbits), expected);
+                // Whatever follows is not part of the extracted bits
+                w.write(u64::MAX, 16);
+                assert_eq!(extract_bits(&w.buf, shift, nbits), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn concat_split_block() {
+        let data = sample(50_000, 5);
+        let blocks = blocks(compress(&data));
+        assert_eq!(blocks.len(), 1);
+        let block = &blocks[0];
+        assert_eq!(decode(block).expect("valid block"), data);
+        for split in [83, 4_000, block.nbits - 9] {
+            let first = Block {
+                start_bit: block.start_bit,
+                data: extract_bits(&block.data, 0, split),
+                nbits: split,
+            };
+            let second = Block {
+                start_bit: block.start_bit + split,
+                data: extract_bits(&block.data, split, block.nbits - split),
+                nbits: block.nbits - split,
+            };
+            assert!(decode(&first).is_err());
+            let merged = concat(first, second);
+            assert_eq!(merged.nbits, block.nbits);
+            assert_eq!(merged.data, block.data);
+            assert_eq!(decode(&merged).expect("valid block"), data);
+        }
+    }
+
+    #[test]
+    fn end_of_stream_magic_in_block() {
+        let mut w = BitWriter::with_capacity(64);
+        w.write_bits(b"BZh9", 32);
+        w.write(BLOCK_MAGIC, 48);
+        w.write(0x1234_5678, 32);
+        w.write(0b101, 3);
+        // By chance in the block data: not followed by a stream header
+        w.write(END_OF_STREAM_MAGIC, 48);
+        w.write(0xdead_beef_cafe, 48);
+        w.write(END_OF_STREAM_MAGIC, 48);
+        w.write(0x1234_5678, 32);
+        let stream = w.buf;
+        // Followed by another stream, or by the end of input
+        for streams in [2, 1] {
+            let blocks = blocks(stream.repeat(streams));
+            assert_eq!(blocks.len(), streams);
+            for block in blocks {
+                assert_eq!(block.nbits, 48 + 32 + 3 + 48 + 48);
+            }
+        }
+    }
+
+    #[test]
+    fn resume() {
+        let data = sample(1_000_000, 8);
+        let compressed = compress(&data);
+        let (mut decoder, starts) =
+            MultiBzDecoder::resumable(io::Cursor::new(compressed.clone()), None);
+        let mut head = vec![0; 600_000];
+        decoder.read_exact(&mut head).expect("valid bzip2");
+        let start = starts.before(600_000).expect("a block before");
+        assert!(start.offset > 0 && start.bit > 32);
+        let mut input = io::Cursor::new(compressed);
+        input.set_position(start.bit / 8);
+        let (mut resumed, starts) = MultiBzDecoder::resumable(input, Some(start));
+        let mut rest = vec![];
+        resumed.read_to_end(&mut rest).expect("valid bzip2");
+        assert_eq!(rest, &data[start.offset as usize..]);
+        assert_eq!(starts.before(start.offset), Some(start));
+
+        // Not at a block, including past its first byte
+        for bit in [start.bit + 1, start.bit / 8 * 8 + 8] {
+            let mut input = io::Cursor::new(compress(&data));
+            input.set_position(bit / 8);
+            let wrong = BlockStart { bit, ..start };
+            let e = MultiBzDecoder::resumable(input, Some(wrong))
+                .0
+                .read_to_end(&mut rest)
+                .expect_err("no block");
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            assert!(e.to_string().starts_with("no bzip2 block"), "{e}");
+        }
+    }
+
+    #[test]
+    fn resume_past_block_magic() {
+        // Its first bit is before the input, and the following ones still look like a magic
+        let mut w = BitWriter::with_capacity(64);
+        w.write(0, 7);
+        w.write(BLOCK_MAGIC, 48);
+        w.write(0x1234_5678, 32);
+        let (feeder, _workers, _ordered) = parallel::ordered::<Block, Block>(1024);
+        let e = scan(io::Cursor::new(w.buf[1..].to_vec()), feeder, Some(1)).expect_err("no block");
+        assert_eq!(e.to_string(), "no bzip2 block found in input");
+    }
+
+    #[test]
+    fn truncated() {
+        let mut compressed = compress(&sample(500_000, 6));
+        compressed.truncate(compressed.len() / 2);
+        let e = decompress(compressed).expect_err("truncated");
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(
+            e.to_string()
+                .starts_with("truncated bzip2 stream: block at compressed byte offset"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn corrupted() {
+        let mut compressed = compress(&sample(500_000, 7));
+        let blocks = blocks(compressed.clone());
+        let offset = blocks[1].byte_offset() as usize + 1000;
+        compressed[offset] ^= 0x10;
+        let e = decompress(compressed).expect_err("corrupted");
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(
+            e.to_string().starts_with(&format!(
+                "corrupted bzip2 block at compressed byte offset {}: ",
+                blocks[1].byte_offset()
+            )),
+            "{e}"
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use std::path::PathBuf;
+
+use chrono::NaiveDate;
+use clap::{Parser, Subcommand, ValueEnum};
+
+/// Extract places that share a border from Wikidata, and generate geojson files to browse them
+#[derive(Parser)]
+#[command(version, about, arg_required_else_help = true)]
+pub(crate) struct Cli {
+    /// Intermediate sqlite database [default: border-explorer.db]
+    #[arg(long, global = true)]
+    pub(crate) db: Option<String>,
+
+    /// Configuration file, TOML or JSON [default: border-explorer.toml if it exists]
+    #[arg(long, global = true)]
+    pub(crate) config: Option<PathBuf>,
+
+    /// Print the effective configuration, and exit
+    #[arg(long, global = true)]
+    pub(crate) print_config: bool,
+
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum Command {
+    /// Extract entities sharing a border from a Wikidata JSON dump into the database
+    Ingest {
+        /// Wikidata JSON dump, bz2, gz, zstd or uncompressed; `-` for stdin
+        dump: String,
+        /// Only keep entities having one of those natures (comma-separated ids, e.g. Q484170)
+        #[arg(long, value_delimiter = ',')]
+        natures: Vec<String>,
+        /// Continue an interrupted ingestion from its last checkpoint. bz2 dumps are read again from
+        /// the compressed block of the checkpoint; gzip, zstd and piped dumps can't seek, and are
+        /// decompressed again from their start
+        #[arg(long)]
+        resume: bool,
+        /// Default date of the generated borders, and of the subclasses kept [default: the dump
+        /// date]
+        #[arg(long)]
+        as_of: Option<NaiveDate>,
+    },
+    /// Apply newer versions of some entities to the database
+    Update {
+        /// File in the dump format, daily incremental dump (pages-meta-hist-incr.xml), or directory
+        /// of entity JSON files (Special:EntityData)
+        source: String,
+        /// Default date of the generated borders, and of the subclasses kept [default: today]
+        #[arg(long)]
+        as_of: Option<NaiveDate>,
+    },
+    /// Generate the geojson files of the top categories, and their index
+    Generate {
+        #[arg(long, default_value = "web/geojson")]
+        output: PathBuf,
+        /// Borders and natures as they were at this date [default: the dump date]
+        #[arg(long)]
+        date: Option<NaiveDate>,
+        /// Places and borders of every period, with their start and end, to filter them in the
+        /// viewer; categories are still ranked at the date
+        #[arg(long, conflicts_with = "date")]
+        all_periods: bool,
+    },
+    /// Print statistics about the database content
+    Stats,
+    /// Print everything the database knows about an entity
+    Query {
+        /// Entity id, e.g. Q90
+        id: String,
+    },
+    /// Generate the geojson files of a single category, whatever its rank
+    Export {
+        /// Category id, e.g. Q484170
+        category: String,
+        #[arg(long, default_value = ".")]
+        output: PathBuf,
+        /// Borders and natures as they were at this date [default: the dump date]
+        #[arg(long)]
+        date: Option<NaiveDate>,
+        /// Places and borders of every period, with their start and end, to filter them in the
+        /// viewer
+        #[arg(long, conflicts_with = "date")]
+        all_periods: bool,
+    },
+    /// Report Wikidata statements that need fixing, per category
+    Report {
+        #[command(subcommand)]
+        report: Report,
+        /// Category id, e.g. Q484170 [default: the generated categories]
+        #[arg(long, global = true)]
+        category: Option<String>,
+        #[arg(long, global = true, value_enum, default_value_t = ReportFormat::Csv)]
+        format: ReportFormat,
+        /// [default: stdout]
+        #[arg(long, global = true)]
+        output: Option<PathBuf>,
+        /// Borders and natures as they were at this date [default: the dump date]
+        #[arg(long, global = true)]
+        date: Option<NaiveDate>,
+    },
+}
+
+#[derive(Subcommand, Clone, Copy)]
+pub(crate) enum Report {
+    /// Borders stated by only one of the places, missing the reciprocal statement
+    ///
+    /// Both places must belong to the category; places without any border of their own, but with
+    /// a nature (P31) and coordinates (P625), are only known when ingested with the
+    /// borderless_places option.
+    Asymmetric,
+    /// Places at (0, 0), with latitude and longitude that look swapped, or at the same position
+    Quality,
+    /// Borders much longer than the median one of their category, that are often wrong
+    Distance {
+        /// List all the borders, not only the distant ones
+        #[arg(long)]
+        all: bool,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+pub(crate) enum ReportFormat {
+    Csv,
+    Json,
+}
@@ -0,0 +1,317 @@
+// Runtime configuration
+//
+// I initially envisionned a pipeline that would be heavily configurable. But this is at odds with
+// putting things in a fixed-schema SQL DB, otherwise we'd just be replicating the original
+// wikidata graph database.
+//
+// So what's configurable here are the knobs around that fixed schema: which entities are
+// extracted, and how categories are filtered and ranked. Every option has a built-in default,
+// that can be overridden from a TOML (or JSON) file.
+use super::EARTH;
+use super::NATURE_CLAIM;
+use super::POSITION_CLAIM;
+use super::SHARES_BORDER_WITH_CLAIM;
+use super::db::int_id_faillible;
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Loaded when present in the current directory, and no config file is passed explicitly
+const DEFAULT_CONFIG_FILENAME: &str = "border-explorer.toml";
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    /// Passed on the command line
+    #[serde(skip)]
+    pub(crate) wikidata_dump_filename: Option<String>,
+    pub(crate) mandatory_claims: Vec<String>,
+    pub(crate) filtered_natures: Vec<String>,
+    pub(crate) intermediate_db_filename: String,
+    /// Date at which claims must be valid: subclasses are kept as of this date when ingesting,
+    /// and borders are generated as they were then; the dump date when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) as_of: Option<NaiveDate>,
+    /// Passed on the command line: generate places and borders of every period, not only those
+    /// valid at the as-of date
+    #[serde(skip)]
+    pub(crate) all_periods: bool,
+    /// Languages of the labels kept and generated, in this order; all of them when empty
+    pub(crate) languages: Vec<String>,
+    /// Languages tried in order when there is no label in a language, e.g. pt-br = ["pt", "en"]
+    pub(crate) label_fallbacks: BTreeMap<String, Vec<String>>,
+    /// Wikis whose page titles are kept and generated, e.g. enwiki
+    pub(crate) sitelinks: Vec<String>,
+    /// Generate each border as a Feature with the ids and names of the places it connects,
+    /// instead of a MultiLineString per period
+    pub(crate) edge_features: bool,
+    /// Also ingest and generate the population, area, country and administrative parent of places
+    pub(crate) attributes: bool,
+    /// Also ingest the natures of places with coordinates but without any border, so that the
+    /// asymmetric report lists the borders stated with them; much slower to ingest
+    pub(crate) borderless_places: bool,
+    /// Only generate borders stated with a reference, by either of the places
+    pub(crate) referenced_edges_only: bool,
+    /// Borders longer than this many times the median border of their category are distant
+    pub(crate) distant_edges_ratio: f64,
+    /// Do not generate distant borders, that are often wrong statements
+    pub(crate) exclude_distant_edges: bool,
+    /// Do not generate places at (0, 0), with latitude and longitude that look swapped, or at the
+    /// same position as another place, nor their borders
+    pub(crate) exclude_bad_positions: bool,
+    /// Globes whose places are generated, e.g. Q405 for the Moon; those other than Earth are
+    /// generated in a subdirectory named after them
+    pub(crate) globes: Vec<String>,
+    /// Maximum number of decimals of the generated coordinates; less precise coordinates get
+    /// fewer
+    pub(crate) coordinate_decimals: u32,
+    /// Parts (P518 qualifier) of the coordinates that best represent a place, like its capital;
+    /// preferred over the other coordinates of the same rank
+    pub(crate) coordinate_parts: BTreeSet<u64>,
+
+    /// Tab-separated list of categories, used instead of the built-in one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) banned_categories_file: Option<PathBuf>,
+    #[serde(skip)]
+    pub(crate) banned_generic_categories: HashSet<u64>,
+    pub(crate) banned_parents: BTreeSet<u64>,
+
+    pub(crate) ranking: Ranking,
+}
+
+/// Which categories are generated
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Ranking {
+    /// Minimum number of borders between entities of a category
+    pub(crate) min_edges: u64,
+    /// Minimum number of borders per entity of a category, for it to look like a graph
+    pub(crate) min_edges_per_entity: f64,
+    pub(crate) max_categories: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            wikidata_dump_filename: None,
+            mandatory_claims: [NATURE_CLAIM, POSITION_CLAIM, SHARES_BORDER_WITH_CLAIM]
+                .map(String::from)
+                .to_vec(),
+            filtered_natures: vec![],
+            intermediate_db_filename: "border-explorer.db".to_string(),
+            as_of: None,
+            all_periods: false,
+            languages: vec!["en".to_string(), "fr".to_string()],
+            // Items that have the same name everywhere often only have a multilingual label
+            label_fallbacks: [
+                ("en", &["mul"][..]),
+                ("pt-br", &["pt", "mul", "en"]),
+                ("zh-hant", &["zh", "mul"]),
+                ("zh-hans", &["zh", "mul"]),
+            ]
+            .into_iter()
+            .map(|(lang, chain)| {
+                (
+                    lang.to_string(),
+                    chain.iter().map(|l| l.to_string()).collect(),
+                )
+            })
+            .collect(),
+            sitelinks: vec![],
+            edge_features: false,
+            attributes: false,
+            borderless_places: false,
+            referenced_edges_only: false,
+            distant_edges_ratio: 5.0,
+            exclude_distant_edges: false,
+            exclude_bad_positions: false,
+            globes: vec![EARTH.to_string()],
+            // About a meter
+            coordinate_decimals: 5,
+            // centroid, capital, administrative centre
+            coordinate_parts: BTreeSet::from([511093, 5119, 1306755]),
+
+            banned_categories_file: None,
+            // Block those generic (too broad) categories that can be in many separate places, or
+            // not useful enough to display in general
+            banned_generic_categories: parse_banned_categories(include_str!(
+                "../banned-categories.tsv"
+            ))
+            .expect("Invalid built-in banned categories"),
+            /* Hardcoded filter list for top parents reponsible for 30% of the table */
+            banned_parents: BTreeSet::from([11173, 20747295, 8054, 7187, 277338]),
+
+            ranking: Ranking::default(),
+        }
+    }
+}
+
+impl Default for Ranking {
+    fn default() -> Self {
+        Self {
+            min_edges: 28,
+            min_edges_per_entity: 1.8,
+            max_categories: 600,
+        }
+    }
+}
+
+impl Config {
+    /// Whether labels in this language are stored: one of the languages, or their fallbacks
+    pub(crate) fn keeps_label(&self, lang: &str) -> bool {
+        self.languages.is_empty()
+            || self.languages.iter().any(|l| {
+                l == lang
+                    || self
+                        .label_fallbacks(l)
+                        .iter()
+                        .any(|fallback| fallback == lang)
+            })
+    }
+
+    pub(crate) fn label_fallbacks(&self, lang: &str) -> &[String] {
+        self.label_fallbacks
+            .get(lang)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Start of the as-of day; today if it wasn't resolved
+    pub(crate) fn as_of(&self) -> DateTime<Utc> {
+        self.as_of
+            .unwrap_or_else(today)
+            .and_time(NaiveTime::MIN)
+            .and_utc()
+    }
+
+    /// Built-in defaults, overridden by the given config file, or the default one if it exists
+    pub(crate) fn load(path: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+        let default_path = Path::new(DEFAULT_CONFIG_FILENAME);
+        let path = path.or_else(|| default_path.exists().then_some(default_path));
+        let mut config: Config = match path {
+            None => Config::default(),
+            Some(path) => {
+                let content = fs::read_to_string(path)
+                    .map_err(|e| format!("Cannot read config {}: {e}", path.display()))?;
+                if path.extension().is_some_and(|ext| ext == "json") {
+                    serde_json::from_str(&content)
+                        .map_err(|e| format!("Invalid config {}: {e}", path.display()))?
+                } else {
+                    toml::from_str(&content)
+                        .map_err(|e| format!("Invalid config {}: {e}", path.display()))?
+                }
+            }
+        };
+        if let Some(ref file) = config.banned_categories_file {
+            // Relative to the config file
+            let file = &path
+                .and_then(Path::parent)
+                .map_or_else(|| file.clone(), |dir| dir.join(file));
+            let content = fs::read_to_string(file)
+                .map_err(|e| format!("Cannot read {}: {e}", file.display()))?;
+            config.banned_generic_categories = parse_banned_categories(&content)
+                .map_err(|e| format!("Invalid banned category in {}: {e}", file.display()))?;
+        }
+        Ok(config)
+    }
+}
+
+fn parse_banned_categories(s: &str) -> Result<HashSet<u64>, String> {
+    s.lines()
+        // Ignore comments
+        .filter(|l| l.bytes().next().unwrap_or(b'#') != b'#')
+        // Keep only first column of Tab-Separated-Values
+        .filter_map(|l| l.split("\t").next())
+        .map(int_id_faillible)
+        .collect()
+}
+
+pub(crate) fn today() -> NaiveDate {
+    DateTime::<Utc>::from(SystemTime::now()).date_naive()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write each (file name, content) in a new directory, removed on drop
+    struct Dir(PathBuf);
+    impl Dir {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("border-explorer-{name}-{}", std::process::id()));
+            fs::create_dir_all(&dir).expect("temporary directory");
+            for (file, content) in files {
+                fs::write(dir.join(file), content).expect("temporary file");
+            }
+            Dir(dir)
+        }
+    }
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut config = Config {
+            languages: vec!["de".to_string()],
+            coordinate_decimals: 3,
+            as_of: NaiveDate::from_ymd_opt(2024, 1, 1),
+            banned_categories_file: Some(PathBuf::from("banned.tsv")),
+            ..Config::default()
+        };
+        config.ranking.min_edges = 5;
+        let toml = toml::to_string_pretty(&config).expect("serializable");
+        let json = serde_json::to_string_pretty(&config).expect("serializable");
+        let dir = Dir::new(
+            "round-trip",
+            &[
+                ("config.toml", &toml),
+                ("config.json", &json),
+                // Relative to the config file
+                ("banned.tsv", "Q5\tperson\n# comment\nQ6\n"),
+            ],
+        );
+        for file in ["config.toml", "config.json"] {
+            let loaded = Config::load(Some(&dir.0.join(file))).expect("valid config");
+            assert_eq!(toml::to_string_pretty(&loaded).expect("serializable"), toml);
+            assert_eq!(loaded.banned_generic_categories, HashSet::from([5, 6]));
+        }
+    }
+
+    #[test]
+    fn unknown_fields() {
+        let dir = Dir::new(
+            "unknown-fields",
+            &[
+                ("top.toml", "languages = [\"en\"]\nlanguage = \"fr\"\n"),
+                ("nested.toml", "[ranking]\nmin_edge = 5\n"),
+                (
+                    "top.json",
+                    "{\"languages\": [\"en\"], \"language\": \"fr\"}",
+                ),
+            ],
+        );
+        for (file, field) in [
+            ("top.toml", "language"),
+            ("nested.toml", "min_edge"),
+            ("top.json", "language"),
+        ] {
+            let e = Config::load(Some(&dir.0.join(file)))
+                .err()
+                .expect("unknown field");
+            assert!(
+                e.to_string().contains(&format!("unknown field `{field}`")),
+                "{e}"
+            );
+        }
+    }
+}
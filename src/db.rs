@@ -1,41 +1,121 @@
+use super::APPLIES_TO_PART_CLAIM;
+use super::AREA_CLAIM;
+use super::COUNTRY_CLAIM;
+use super::Config;
+use super::EARTH;
 use super::Element;
+use super::Interval;
+use super::LENGTH_CLAIM;
+use super::LOCATED_IN_CLAIM;
 use super::Labels;
 use super::NATURE_CLAIM;
+use super::POPULATION_CLAIM;
 use super::POSITION_CLAIM;
+use super::Rank;
 use super::SHARES_BORDER_WITH_CLAIM;
 use super::SUBCLASS_OF_CLAIM;
+use super::SUBJECT_OF_CLAIM;
 use super::Snak;
 use super::claim_and_roles;
+use super::claim_interval;
+use super::claim_point_in_time;
 use super::claim_still_valid;
+use super::entity_interval;
 
-use std::collections::HashSet;
+use crate::bz2::BlockStart;
+
+use std::collections::{BTreeSet, HashSet};
 use std::error::Error;
 
+use chrono::{DateTime, Utc};
+use rusqlite::types::Value;
+
 pub(crate) fn create_tables(
     conn: &mut rusqlite::Connection,
     banned_categories: &HashSet<u64>,
 ) -> Result<(), Box<dyn Error>> {
     conn.execute(
         "CREATE TABLE entities (
-            id INTEGER PRIMARY KEY,
-            name_en TEXT,
-            name_fr TEXT
+            id INTEGER PRIMARY KEY
+        );",
+        (),
+    )?;
+    conn.execute(
+        "CREATE TABLE labels (
+            id INTEGER NOT NULL,
+            lang TEXT NOT NULL,
+            label TEXT NOT NULL,
+            PRIMARY KEY(id, lang),
+            FOREIGN KEY(id) REFERENCES entities(id)
+        );",
+        (),
+    )?;
+    conn.execute(
+        "CREATE TABLE descriptions (
+            id INTEGER NOT NULL,
+            lang TEXT NOT NULL,
+            description TEXT NOT NULL,
+            PRIMARY KEY(id, lang),
+            FOREIGN KEY(id) REFERENCES entities(id)
+        );",
+        (),
+    )?;
+    conn.execute(
+        "CREATE TABLE aliases (
+            id INTEGER NOT NULL,
+            lang TEXT NOT NULL,
+            alias TEXT NOT NULL,
+            FOREIGN KEY(id) REFERENCES entities(id)
+        );",
+        (),
+    )?;
+    conn.execute("CREATE INDEX aliases_id ON aliases(id);", ())?;
+    conn.execute(
+        "CREATE TABLE sitelinks (
+            id INTEGER NOT NULL,
+            site TEXT NOT NULL,
+            title TEXT NOT NULL,
+            PRIMARY KEY(id, site),
+            FOREIGN KEY(id) REFERENCES entities(id)
         );",
         (),
     )?;
     conn.execute(
         "CREATE TABLE positions (
             id INTEGER PRIMARY KEY,
-            lat TEXT,
-            lon TEXT,
+            lat REAL NOT NULL,
+            lon REAL NOT NULL,
+            precision REAL,
+            globe INTEGER NOT NULL,
+            valid_from INTEGER,
+            valid_until INTEGER,
             FOREIGN KEY(id) REFERENCES entities(id)
         );",
         (),
     )?;
+    conn.execute(
+        "CREATE TABLE coordinates (
+            id INTEGER NOT NULL,
+            lat REAL NOT NULL,
+            lon REAL NOT NULL,
+            precision REAL,
+            globe INTEGER NOT NULL,
+            rank TEXT NOT NULL,
+            part INTEGER,
+            candidate INTEGER NOT NULL,
+            valid_from INTEGER,
+            valid_until INTEGER,
+            FOREIGN KEY(id) REFERENCES entities(id)
+        );",
+        (),
+    )?;
+    conn.execute("CREATE INDEX coordinates_id ON coordinates(id);", ())?;
     conn.execute(
         "CREATE TABLE natures (
             id INTEGER,
             nat INTEGER,
+            valid_from INTEGER,
+            valid_until INTEGER,
             FOREIGN KEY(id) REFERENCES entities(id)
         );
         ",
@@ -43,16 +123,81 @@ pub(crate) fn create_tables(
     )?;
     conn.execute("CREATE INDEX natures_nat ON natures(nat);", ())?;
     conn.execute("CREATE INDEX natures_id_nat ON natures(id, nat);", ())?;
+    // Of places without any border, not ingested otherwise: only to tell whether the neighbours
+    // stating a border with them are in the same category, and left out of the ranking
+    conn.execute(
+        "CREATE TABLE borderless_natures (
+            id INTEGER NOT NULL,
+            nat INTEGER NOT NULL,
+            valid_from INTEGER,
+            valid_until INTEGER
+        );",
+        (),
+    )?;
+    conn.execute(
+        "CREATE INDEX borderless_natures_nat ON borderless_natures(nat);",
+        (),
+    )?;
+    conn.execute(
+        "CREATE INDEX borderless_natures_id ON borderless_natures(id);",
+        (),
+    )?;
+    conn.execute(
+        "CREATE TABLE borderless_labels (
+            id INTEGER NOT NULL,
+            lang TEXT NOT NULL,
+            label TEXT NOT NULL,
+            PRIMARY KEY(id, lang)
+        );",
+        (),
+    )?;
     conn.execute(
         "CREATE TABLE edges (
             a INTEGER NOT NULL,
             b INTEGER NOT NULL,
-            UNIQUE(a, b)
+            valid_from INTEGER,
+            valid_until INTEGER
         );",
         (),
     )?;
     conn.execute("CREATE INDEX edges_a ON edges(a);", ())?;
     conn.execute("CREATE INDEX edges_b ON edges(b);", ())?;
+    conn.execute(
+        "CREATE TABLE edge_qualifiers (
+            a INTEGER NOT NULL,
+            b INTEGER NOT NULL,
+            source INTEGER NOT NULL,
+            valid_from INTEGER,
+            valid_until INTEGER,
+            length REAL,
+            subject_of INTEGER,
+            refs INTEGER NOT NULL
+        );",
+        (),
+    )?;
+    conn.execute(
+        "CREATE INDEX edge_qualifiers_a_b ON edge_qualifiers(a, b);",
+        (),
+    )?;
+    conn.execute(
+        "CREATE INDEX edge_qualifiers_source ON edge_qualifiers(source);",
+        (),
+    )?;
+    conn.execute(
+        "CREATE TABLE bad_positions (
+            id INTEGER PRIMARY KEY
+        );",
+        (),
+    )?;
+    conn.execute(
+        "CREATE TABLE distant_edges (
+            category INTEGER NOT NULL,
+            a INTEGER NOT NULL,
+            b INTEGER NOT NULL,
+            PRIMARY KEY(category, a, b)
+        );",
+        (),
+    )?;
     conn.execute(
         "CREATE TABLE subclass (
             id INTEGER NOT NULL,
@@ -63,7 +208,60 @@ pub(crate) fn create_tables(
         ",
         (),
     )?;
+    conn.execute(
+        "CREATE TABLE populations (
+            id INTEGER PRIMARY KEY,
+            population INTEGER NOT NULL,
+            date INTEGER,
+            FOREIGN KEY(id) REFERENCES entities(id)
+        );",
+        (),
+    )?;
+    conn.execute(
+        "CREATE TABLE areas (
+            id INTEGER PRIMARY KEY,
+            area REAL NOT NULL,
+            FOREIGN KEY(id) REFERENCES entities(id)
+        );",
+        (),
+    )?;
+    conn.execute(
+        "CREATE TABLE countries (
+            id INTEGER NOT NULL,
+            country INTEGER NOT NULL,
+            valid_from INTEGER,
+            valid_until INTEGER,
+            FOREIGN KEY(id) REFERENCES entities(id)
+        );",
+        (),
+    )?;
+    conn.execute("CREATE INDEX countries_id ON countries(id);", ())?;
+    conn.execute(
+        "CREATE TABLE located_in (
+            id INTEGER NOT NULL,
+            parent INTEGER NOT NULL,
+            valid_from INTEGER,
+            valid_until INTEGER,
+            FOREIGN KEY(id) REFERENCES entities(id)
+        );",
+        (),
+    )?;
+    conn.execute("CREATE INDEX located_in_id ON located_in(id);", ())?;
+    conn.execute(
+        "CREATE TABLE deprecated_statements (
+            property TEXT PRIMARY KEY,
+            count INTEGER NOT NULL
+        );",
+        (),
+    )?;
     conn.execute("CREATE TABLE banned_natures (id INTEGER NOT NULL);", ())?;
+    conn.execute(
+        "CREATE TABLE metadata (
+            key TEXT PRIMARY KEY,
+            value
+        );",
+        (),
+    )?;
     conn.execute("CREATE INDEX subclass_parent ON subclass(parent);", ())?;
     conn.execute(
         ("INSERT INTO banned_natures VALUES ".to_string()
@@ -79,141 +277,1017 @@ pub(crate) fn create_tables(
 
     Ok(())
 }
+/// How far in the dump the ingestion went
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Checkpoint {
+    /// Number of lines processed
+    pub(crate) lines: usize,
+    /// Decompressed byte offset of the next line to process
+    pub(crate) offset: u64,
+    /// Compressed block of bz2 dumps containing that offset, to restart decompression from
+    pub(crate) block: Option<BlockStart>,
+    pub(crate) complete: bool,
+}
+
+/// Last checkpoint written during a previous ingestion, and the dump it was for
+pub(crate) fn read_checkpoint(
+    conn: &rusqlite::Connection,
+) -> Result<Option<(Checkpoint, String)>, Box<dyn Error>> {
+    let has_metadata: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'metadata';",
+        (),
+        |row| row.get(0),
+    )?;
+    if !has_metadata {
+        return Ok(None);
+    }
+    let get = |key: &str| {
+        conn.query_row(
+            "SELECT value FROM metadata WHERE key = ?1;",
+            (key,),
+            |row| row.get::<_, Value>(0),
+        )
+    };
+    // Not recorded for other formats, nor by older versions
+    let block = match (get("block_bit"), get("block_offset")) {
+        (Ok(Value::Integer(bit)), Ok(Value::Integer(offset))) => Some(BlockStart {
+            bit: bit.try_into()?,
+            offset: offset.try_into()?,
+        }),
+        _ => None,
+    };
+    let checkpoint = match (get("lines"), get("offset"), get("complete"), get("dump")) {
+        (
+            Ok(Value::Integer(lines)),
+            Ok(Value::Integer(offset)),
+            Ok(Value::Integer(complete)),
+            Ok(Value::Text(dump)),
+        ) => (
+            Checkpoint {
+                lines: lines.try_into()?,
+                offset: offset.try_into()?,
+                block,
+                complete: complete != 0,
+            },
+            dump,
+        ),
+        // Tables created without the initial checkpoint, by an older version
+        (Err(rusqlite::Error::QueryReturnedNoRows), ..) => return Ok(None),
+        _ => return Err("invalid checkpoint in metadata table".into()),
+    };
+    Ok(Some(checkpoint))
+}
+
+pub(crate) fn get_metadata(
+    conn: &rusqlite::Connection,
+    key: &str,
+) -> rusqlite::Result<Option<String>> {
+    match conn.query_row(
+        "SELECT value FROM metadata WHERE key = ?1;",
+        (key,),
+        |row| row.get(0),
+    ) {
+        Ok(value) => Ok(Some(value)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub(crate) fn set_metadata(
+    conn: &rusqlite::Connection,
+    key: &str,
+    value: impl rusqlite::ToSql,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO metadata (key, value) VALUES (?1, ?2);",
+        (key, value),
+    )?;
+    Ok(())
+}
+
+/// Start a transaction, that will be committed by the next checkpoint
+pub(crate) fn begin(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    conn.execute_batch("BEGIN;")
+}
+
+/// Record progress along with the data inserted since the previous checkpoint, atomically
+pub(crate) fn checkpoint(
+    conn: &rusqlite::Connection,
+    dump: &str,
+    checkpoint: &Checkpoint,
+) -> Result<(), Box<dyn Error>> {
+    // NULL when there is no block to restart from
+    let block_bit = checkpoint.block.map(|b| i64::try_from(b.bit)).transpose()?;
+    let block_offset = checkpoint
+        .block
+        .map(|b| i64::try_from(b.offset))
+        .transpose()?;
+    conn.execute(
+        "INSERT OR REPLACE INTO metadata (key, value)
+            VALUES ('lines', ?1), ('offset', ?2), ('complete', ?3), ('dump', ?4),
+                ('block_bit', ?5), ('block_offset', ?6);",
+        (
+            i64::try_from(checkpoint.lines)?,
+            i64::try_from(checkpoint.offset)?,
+            checkpoint.complete,
+            dump,
+            block_bit,
+            block_offset,
+        ),
+    )?;
+    commit(conn)?;
+    Ok(())
+}
+
+pub(crate) fn commit(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    conn.execute_batch("COMMIT;")
+}
+
 pub(crate) struct Statements<'conn> {
     pub(crate) insert_entity: rusqlite::Statement<'conn>,
+    pub(crate) insert_label: rusqlite::Statement<'conn>,
+    pub(crate) insert_description: rusqlite::Statement<'conn>,
+    pub(crate) insert_alias: rusqlite::Statement<'conn>,
+    insert_sitelink: rusqlite::Statement<'conn>,
     insert_position: rusqlite::Statement<'conn>,
+    insert_coordinate: rusqlite::Statement<'conn>,
     insert_nature: rusqlite::Statement<'conn>,
+    insert_borderless_nature: rusqlite::Statement<'conn>,
+    insert_borderless_label: rusqlite::Statement<'conn>,
     insert_edge: rusqlite::Statement<'conn>,
+    insert_edge_qualifiers: rusqlite::Statement<'conn>,
     insert_subclass: rusqlite::Statement<'conn>,
-    pub(crate) select_entity: rusqlite::Statement<'conn>,
+    insert_population: rusqlite::Statement<'conn>,
+    insert_area: rusqlite::Statement<'conn>,
+    insert_country: rusqlite::Statement<'conn>,
+    insert_located_in: rusqlite::Statement<'conn>,
+    count_deprecated: rusqlite::Statement<'conn>,
+    delete_entity: rusqlite::Statement<'conn>,
+    delete_labels: rusqlite::Statement<'conn>,
+    delete_descriptions: rusqlite::Statement<'conn>,
+    delete_aliases: rusqlite::Statement<'conn>,
+    delete_sitelinks: rusqlite::Statement<'conn>,
+    delete_position: rusqlite::Statement<'conn>,
+    delete_coordinates: rusqlite::Statement<'conn>,
+    delete_natures: rusqlite::Statement<'conn>,
+    delete_borderless_natures: rusqlite::Statement<'conn>,
+    delete_borderless_labels: rusqlite::Statement<'conn>,
+    delete_edges: rusqlite::Statement<'conn>,
+    delete_edge_qualifiers: rusqlite::Statement<'conn>,
+    delete_subclass: rusqlite::Statement<'conn>,
+    delete_population: rusqlite::Statement<'conn>,
+    delete_area: rusqlite::Statement<'conn>,
+    delete_countries: rusqlite::Statement<'conn>,
+    delete_located_in: rusqlite::Statement<'conn>,
+    pub(crate) select_labels: rusqlite::Statement<'conn>,
+    pub(crate) select_descriptions: rusqlite::Statement<'conn>,
     pub(crate) select_entities_category: rusqlite::Statement<'conn>,
     pub(crate) select_edges_category: rusqlite::Statement<'conn>,
     pub(crate) top_categories_by_edges: rusqlite::Statement<'conn>,
+    pub(crate) select_asymmetric_category: rusqlite::Statement<'conn>,
+    pub(crate) insert_distant_edge: rusqlite::Statement<'conn>,
+    pub(crate) delete_distant_edges: rusqlite::Statement<'conn>,
+    pub(crate) select_null_island: rusqlite::Statement<'conn>,
+    pub(crate) select_duplicate_positions: rusqlite::Statement<'conn>,
+    pub(crate) select_neighbour_positions: rusqlite::Statement<'conn>,
+    pub(crate) insert_bad_position: rusqlite::Statement<'conn>,
+    pub(crate) delete_bad_positions: rusqlite::Statement<'conn>,
 }
 impl<'conn> Statements<'conn> {
     pub(crate) fn new(conn: &'conn rusqlite::Connection) -> Self {
         Self {
             insert_entity: conn
                 .prepare(
-                    "INSERT INTO entities (id, name_en, name_fr)
-                        VALUES (?1, ?2, ?3);",
+                    "INSERT INTO entities (id)
+                        VALUES (?1);",
                 )
                 .expect("Failed to prepare insert entity"),
-            insert_position: conn
+            insert_label: conn
+                .prepare(
+                    "INSERT INTO labels (id, lang, label)
+                        VALUES (?1, ?2, ?3);",
+                )
+                .expect("Failed to prepare insert label"),
+            insert_description: conn
+                .prepare(
+                    "INSERT INTO descriptions (id, lang, description)
+                        VALUES (?1, ?2, ?3);",
+                )
+                .expect("Failed to prepare insert description"),
+            insert_alias: conn
                 .prepare(
-                    "INSERT INTO positions (id, lat, lon)
+                    "INSERT INTO aliases (id, lang, alias)
                         VALUES (?1, ?2, ?3);",
                 )
+                .expect("Failed to prepare insert alias"),
+            insert_sitelink: conn
+                .prepare(
+                    "INSERT INTO sitelinks (id, site, title)
+                        VALUES (?1, ?2, ?3);",
+                )
+                .expect("Failed to prepare insert sitelink"),
+            insert_position: conn
+                .prepare(
+                    "INSERT INTO positions (id, lat, lon, precision, globe, valid_from, valid_until)
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7);",
+                )
                 .expect("Failed to prepare insert position"),
+            insert_coordinate: conn
+                .prepare(
+                    "INSERT INTO coordinates (id, lat, lon, precision, globe, rank, part, candidate,
+                        valid_from, valid_until)
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10);",
+                )
+                .expect("Failed to prepare insert coordinate"),
             insert_nature: conn
                 .prepare(
-                    "INSERT INTO natures (id, nat)
-                        VALUES (?1, ?2);",
+                    "INSERT INTO natures (id, nat, valid_from, valid_until)
+                        VALUES (?1, ?2, ?3, ?4);",
                 )
                 .expect("Failed to prepare insert nature"),
+            insert_borderless_nature: conn
+                .prepare(
+                    "INSERT INTO borderless_natures (id, nat, valid_from, valid_until)
+                        VALUES (?1, ?2, ?3, ?4);",
+                )
+                .expect("Failed to prepare insert borderless nature"),
+            insert_borderless_label: conn
+                .prepare(
+                    "INSERT INTO borderless_labels (id, lang, label)
+                        VALUES (?1, ?2, ?3);",
+                )
+                .expect("Failed to prepare insert borderless label"),
             insert_edge: conn
                 .prepare(
-                    "INSERT OR IGNORE INTO edges (a, b)
-                        VALUES (?1, ?2);",
+                    "INSERT INTO edges (a, b, valid_from, valid_until)
+                        SELECT ?1, ?2, ?3, ?4 WHERE NOT EXISTS (SELECT 1 FROM edges
+                            WHERE a = ?1 AND b = ?2 AND valid_from IS ?3 AND valid_until IS ?4);",
                 )
                 .expect("Failed to prepare insert edge"),
+            insert_edge_qualifiers: conn
+                .prepare(
+                    "INSERT INTO edge_qualifiers (a, b, source, valid_from, valid_until, length,
+                        subject_of, refs)
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8);",
+                )
+                .expect("Failed to prepare insert edge qualifiers"),
             insert_subclass: conn
                 .prepare(
                     "INSERT OR IGNORE INTO subclass (id, parent)
                         VALUES (?1, ?2);",
                 )
                 .expect("Failed to prepare insert edge"),
-            select_entity: conn
-                .prepare("SELECT name_en, name_fr FROM entities WHERE id = ?1;")
-                .expect("Failed to prepare select entity"),
+            insert_population: conn
+                .prepare(
+                    "INSERT INTO populations (id, population, date)
+                        VALUES (?1, ?2, ?3);",
+                )
+                .expect("Failed to prepare insert population"),
+            insert_area: conn
+                .prepare(
+                    "INSERT INTO areas (id, area)
+                        VALUES (?1, ?2);",
+                )
+                .expect("Failed to prepare insert area"),
+            insert_country: conn
+                .prepare(
+                    "INSERT INTO countries (id, country, valid_from, valid_until)
+                        VALUES (?1, ?2, ?3, ?4);",
+                )
+                .expect("Failed to prepare insert country"),
+            insert_located_in: conn
+                .prepare(
+                    "INSERT INTO located_in (id, parent, valid_from, valid_until)
+                        VALUES (?1, ?2, ?3, ?4);",
+                )
+                .expect("Failed to prepare insert located in"),
+            count_deprecated: conn
+                .prepare(
+                    "INSERT INTO deprecated_statements (property, count) VALUES (?1, ?2)
+                        ON CONFLICT(property) DO UPDATE SET count = count + ?2;",
+                )
+                .expect("Failed to prepare count deprecated"),
+            delete_entity: conn
+                .prepare("DELETE FROM entities WHERE id = ?1;")
+                .expect("Failed to prepare delete entity"),
+            delete_labels: conn
+                .prepare("DELETE FROM labels WHERE id = ?1;")
+                .expect("Failed to prepare delete labels"),
+            delete_descriptions: conn
+                .prepare("DELETE FROM descriptions WHERE id = ?1;")
+                .expect("Failed to prepare delete descriptions"),
+            delete_aliases: conn
+                .prepare("DELETE FROM aliases WHERE id = ?1;")
+                .expect("Failed to prepare delete aliases"),
+            delete_sitelinks: conn
+                .prepare("DELETE FROM sitelinks WHERE id = ?1;")
+                .expect("Failed to prepare delete sitelinks"),
+            delete_position: conn
+                .prepare("DELETE FROM positions WHERE id = ?1;")
+                .expect("Failed to prepare delete position"),
+            delete_coordinates: conn
+                .prepare("DELETE FROM coordinates WHERE id = ?1;")
+                .expect("Failed to prepare delete coordinates"),
+            delete_natures: conn
+                .prepare("DELETE FROM natures WHERE id = ?1;")
+                .expect("Failed to prepare delete natures"),
+            delete_borderless_natures: conn
+                .prepare("DELETE FROM borderless_natures WHERE id = ?1;")
+                .expect("Failed to prepare delete borderless natures"),
+            delete_borderless_labels: conn
+                .prepare("DELETE FROM borderless_labels WHERE id = ?1;")
+                .expect("Failed to prepare delete borderless labels"),
+            // Edges of this entity that are still stated by a neighbour are kept
+            delete_edges: conn
+                .prepare(
+                    "DELETE FROM edges WHERE (a = ?1 OR b = ?1) AND NOT EXISTS (
+                        SELECT 1 FROM edge_qualifiers AS q
+                        WHERE q.a = edges.a AND q.b = edges.b
+                            AND q.valid_from IS edges.valid_from
+                            AND q.valid_until IS edges.valid_until);",
+                )
+                .expect("Failed to prepare delete edges"),
+            delete_edge_qualifiers: conn
+                .prepare("DELETE FROM edge_qualifiers WHERE source = ?1;")
+                .expect("Failed to prepare delete edge qualifiers"),
+            delete_subclass: conn
+                .prepare("DELETE FROM subclass WHERE id = ?1;")
+                .expect("Failed to prepare delete subclass"),
+            delete_population: conn
+                .prepare("DELETE FROM populations WHERE id = ?1;")
+                .expect("Failed to prepare delete population"),
+            delete_area: conn
+                .prepare("DELETE FROM areas WHERE id = ?1;")
+                .expect("Failed to prepare delete area"),
+            delete_countries: conn
+                .prepare("DELETE FROM countries WHERE id = ?1;")
+                .expect("Failed to prepare delete countries"),
+            delete_located_in: conn
+                .prepare("DELETE FROM located_in WHERE id = ?1;")
+                .expect("Failed to prepare delete located in"),
+            // A single row with a NULL language for entities without labels, none for unknown ones
+            select_labels: conn
+                .prepare(
+                    "SELECT l.lang, l.label FROM entities AS e LEFT JOIN labels AS l ON l.id = e.id
+                        WHERE e.id = ?1 ORDER BY l.lang;",
+                )
+                .expect("Failed to prepare select labels"),
+            select_descriptions: conn
+                .prepare("SELECT lang, description FROM descriptions WHERE id = ?1 ORDER BY lang;")
+                .expect("Failed to prepare select descriptions"),
             select_entities_category: conn
                 .prepare("
                 WITH all_children(nat) AS (
                     VALUES(?1)
                     UNION SELECT sub.id FROM subclass AS sub JOIN all_children ON all_children.nat = sub.parent)
-                SELECT DISTINCT e.name_en, e.name_fr, p.lon, p.lat
+                SELECT DISTINCT e.id, (SELECT json_group_object(lang, label) FROM labels WHERE labels.id = e.id),
+                    (SELECT json_group_object(site, title) FROM sitelinks WHERE sitelinks.id = e.id),
+                    p.lon, p.lat, p.precision, p.valid_from, p.valid_until,
+                    (SELECT population FROM populations WHERE populations.id = e.id),
+                    (SELECT area FROM areas WHERE areas.id = e.id),
+                    (SELECT json_group_array(DISTINCT 'Q' || country) FROM countries AS c WHERE c.id = e.id
+                        AND ?2 BETWEEN IFNULL(c.valid_from, ?2) AND IFNULL(c.valid_until, ?2)),
+                    (SELECT json_group_array(DISTINCT 'Q' || parent) FROM located_in AS l WHERE l.id = e.id
+                        AND ?2 BETWEEN IFNULL(l.valid_from, ?2) AND IFNULL(l.valid_until, ?2))
                     FROM entities AS e, positions AS p, natures as nat
-                    WHERE p.id = e.id AND nat.id = e.id AND nat.nat IN all_children
+                    WHERE p.id = e.id AND p.globe = ?3 AND nat.id = e.id AND nat.nat IN all_children
+                        AND NOT (?4 AND e.id IN bad_positions)
+                        AND (?5 OR ?2 BETWEEN IFNULL(p.valid_from, ?2) AND IFNULL(p.valid_until, ?2))
+                        AND (?5 OR ?2 BETWEEN IFNULL(nat.valid_from, ?2) AND IFNULL(nat.valid_until, ?2))
                     ORDER BY e.id;")
                 .expect("Failed to prepare select category"),
+            // Places of each border come last, only needed for features and reports: their labels
+            // are only looked up with ?7. A single row per border: the statements of both places
+            // are merged, from the earliest start to the latest end, while both places existed;
+            // only the edges of members of the category are grouped, found through the indexes.
+            // With ?8, every period is kept instead of only the one at the date ?2
             select_edges_category: conn
                 .prepare("
                 WITH all_children(nat) AS (
                     VALUES(?1)
-                    UNION SELECT sub.id FROM subclass AS sub JOIN all_children ON all_children.nat = sub.parent)
-                SELECT DISTINCT a.lon, a.lat, b.lon, b.lat
-                    FROM edges AS edj, positions AS a, positions AS b, natures as anat, natures as bnat
-                    WHERE edj.a = anat.id AND anat.nat in all_children AND bnat.id = edj.b AND bnat.nat IN all_children
-                        AND edj.a = a.id AND edj.b = b.id
-                    ORDER BY edj.a;")
+                    UNION SELECT sub.id FROM subclass AS sub JOIN all_children ON all_children.nat = sub.parent),
+                members AS (
+                    SELECT DISTINCT id FROM natures WHERE nat IN all_children),
+                merged AS (
+                    SELECT e.a, e.b,
+                        CASE WHEN COUNT(*) = COUNT(e.valid_from) THEN MIN(e.valid_from) END AS valid_from,
+                        CASE WHEN COUNT(*) = COUNT(e.valid_until) THEN MAX(e.valid_until) END AS valid_until
+                    FROM members AS m JOIN edges AS e ON e.a = m.id
+                    WHERE e.b IN members
+                        AND (?8 OR ?2 BETWEEN IFNULL(e.valid_from, ?2) AND IFNULL(e.valid_until, ?2))
+                    GROUP BY e.a, e.b)
+                SELECT a.lon, a.lat, b.lon, b.lat, a.precision, b.precision,
+                    NULLIF(MAX(IFNULL(edj.valid_from, -1e20), IFNULL(a.valid_from, -1e20),
+                        IFNULL(b.valid_from, -1e20)), -1e20) AS valid_from,
+                    NULLIF(MIN(IFNULL(edj.valid_until, 1e20), IFNULL(a.valid_until, 1e20),
+                        IFNULL(b.valid_until, 1e20)), 1e20) AS valid_until,
+                    edj.a, edj.b,
+                    CASE WHEN ?7 THEN
+                        (SELECT json_group_object(lang, label) FROM labels WHERE labels.id = edj.a) END,
+                    CASE WHEN ?7 THEN
+                        (SELECT json_group_object(lang, label) FROM labels WHERE labels.id = edj.b) END
+                    FROM merged AS edj, positions AS a, positions AS b
+                    WHERE edj.a = a.id AND edj.b = b.id AND a.globe = ?3 AND b.globe = ?3
+                        AND (?8 OR ?2 BETWEEN IFNULL(a.valid_from, ?2) AND IFNULL(a.valid_until, ?2))
+                        AND (?8 OR ?2 BETWEEN IFNULL(b.valid_from, ?2) AND IFNULL(b.valid_until, ?2))
+                        AND EXISTS (SELECT 1 FROM natures AS anat WHERE anat.id = edj.a AND anat.nat IN all_children
+                            AND (?8 OR ?2 BETWEEN IFNULL(anat.valid_from, ?2) AND IFNULL(anat.valid_until, ?2)))
+                        AND EXISTS (SELECT 1 FROM natures AS bnat WHERE bnat.id = edj.b AND bnat.nat IN all_children
+                            AND (?8 OR ?2 BETWEEN IFNULL(bnat.valid_from, ?2) AND IFNULL(bnat.valid_until, ?2)))
+                        AND (NOT ?4 OR EXISTS (SELECT 1 FROM edge_qualifiers AS q
+                            WHERE q.a = edj.a AND q.b = edj.b AND q.refs > 0
+                            AND (?8 OR ?2 BETWEEN IFNULL(q.valid_from, ?2) AND IFNULL(q.valid_until, ?2))))
+                        AND NOT (?5 AND EXISTS (SELECT 1 FROM distant_edges AS d
+                            WHERE d.category = ?1 AND d.a = edj.a AND d.b = edj.b))
+                        AND NOT (?6 AND (edj.a IN bad_positions OR edj.b IN bad_positions))
+                    ORDER BY 7, 8, edj.a, edj.b;")
                 .expect("Failed to prepare select category"),
             top_categories_by_edges: conn
                 .prepare("
-                WITH all_parents(id, nat) AS (
+                WITH valid_edges AS (
+                    SELECT DISTINCT edges.a, edges.b FROM edges, positions AS pa, positions AS pb
+                        WHERE pa.id = edges.a AND pb.id = edges.b AND pa.globe = ?5 AND pb.globe = ?5
+                        AND ?4 BETWEEN IFNULL(edges.valid_from, ?4) AND IFNULL(edges.valid_until, ?4)
+                        AND ?4 BETWEEN IFNULL(pa.valid_from, ?4) AND IFNULL(pa.valid_until, ?4)
+                        AND ?4 BETWEEN IFNULL(pb.valid_from, ?4) AND IFNULL(pb.valid_until, ?4)
+                        AND (NOT ?6 OR EXISTS (SELECT 1 FROM edge_qualifiers AS q
+                            WHERE q.a = edges.a AND q.b = edges.b AND q.refs > 0
+                            AND ?4 BETWEEN IFNULL(q.valid_from, ?4) AND IFNULL(q.valid_until, ?4)))),
+                all_parents(id, nat) AS (
                     SELECT DISTINCT natures.id, natures.nat FROM natures WHERE nat NOT IN banned_natures
+                        AND ?4 BETWEEN IFNULL(valid_from, ?4) AND IFNULL(valid_until, ?4)
                     UNION SELECT all_parents.id, sub.parent FROM subclass AS sub
                         JOIN all_parents ON all_parents.nat = sub.id WHERE sub.parent NOT IN banned_natures)
-                SELECT ap.nat, COUNT(edj.rowid) AS c FROM edges as edj, all_parents as ap
+                SELECT ap.nat, COUNT(*) AS c FROM valid_edges as edj, all_parents as ap
                     WHERE edj.a = ap.id AND ap.nat IN (SELECT nat FROM all_parents WHERE id = edj.b)
-                    GROUP BY ap.nat HAVING c >= 28 AND 10 * c / COUNT(distinct ap.id) >= 18 ORDER BY c DESC LIMIT 600;")
+                    GROUP BY ap.nat HAVING c >= ?1 AND CAST(c AS REAL) / COUNT(distinct ap.id) >= ?2 ORDER BY c DESC LIMIT ?3;")
                 .expect("Failed to prepare top categories"),
+            select_asymmetric_category: conn
+                .prepare("
+                WITH all_children(nat) AS (
+                    VALUES(?1)
+                    UNION SELECT sub.id FROM subclass AS sub JOIN all_children ON all_children.nat = sub.parent),
+                members AS (
+                    SELECT id FROM natures WHERE nat IN all_children
+                        AND ?2 BETWEEN IFNULL(valid_from, ?2) AND IFNULL(valid_until, ?2)
+                    UNION SELECT id FROM borderless_natures WHERE nat IN all_children
+                        AND ?2 BETWEEN IFNULL(valid_from, ?2) AND IFNULL(valid_until, ?2))
+                SELECT s.source, s.target,
+                    (SELECT json_group_object(lang, label) FROM labels WHERE labels.id = s.source),
+                    (SELECT json_group_object(lang, label) FROM
+                        (SELECT lang, label FROM labels WHERE labels.id = s.target
+                        UNION ALL SELECT lang, label FROM borderless_labels WHERE borderless_labels.id = s.target))
+                    FROM (SELECT DISTINCT q.source, CASE WHEN q.a = q.source THEN q.b ELSE q.a END AS target
+                        FROM edge_qualifiers AS q
+                        WHERE q.a != q.b AND q.a IN members AND q.b IN members
+                            AND ?2 BETWEEN IFNULL(q.valid_from, ?2) AND IFNULL(q.valid_until, ?2)
+                            AND NOT EXISTS (SELECT 1 FROM edge_qualifiers AS r
+                                WHERE r.a = q.a AND r.b = q.b AND r.source != q.source
+                                AND ?2 BETWEEN IFNULL(r.valid_from, ?2) AND IFNULL(r.valid_until, ?2))) AS s
+                    ORDER BY s.source, s.target;")
+                .expect("Failed to prepare select asymmetric"),
+            insert_distant_edge: conn
+                .prepare(
+                    "INSERT OR IGNORE INTO distant_edges (category, a, b)
+                        VALUES (?1, ?2, ?3);",
+                )
+                .expect("Failed to prepare insert distant edge"),
+            delete_distant_edges: conn
+                .prepare("DELETE FROM distant_edges WHERE category = ?1;")
+                .expect("Failed to prepare delete distant edges"),
+            select_null_island: conn
+                .prepare("SELECT id FROM positions WHERE lat = 0 AND lon = 0;")
+                .expect("Failed to prepare select null island"),
+            select_duplicate_positions: conn
+                .prepare("
+                WITH d AS MATERIALIZED (SELECT p.* FROM positions AS p,
+                    (SELECT lat, lon, globe FROM positions
+                        GROUP BY lat, lon, globe HAVING COUNT(*) > 1) AS g
+                    WHERE g.lat = p.lat AND g.lon = p.lon AND g.globe = p.globe)
+                SELECT a.id, a.lat, a.lon, group_concat(b.id, ' ') FROM d AS a, d AS b
+                    WHERE b.lat = a.lat AND b.lon = a.lon AND b.globe = a.globe AND b.id != a.id
+                        AND (a.valid_from > b.valid_until) IS NOT TRUE
+                        AND (b.valid_from > a.valid_until) IS NOT TRUE
+                    GROUP BY a.id ORDER BY a.id;")
+                .expect("Failed to prepare select duplicate positions"),
+            select_neighbour_positions: conn
+                .prepare("
+                SELECT p.id, p.lat, p.lon, n.lat, n.lon
+                    FROM (SELECT a AS id, b AS other FROM edges UNION SELECT b, a FROM edges) AS x,
+                        positions AS p, positions AS n
+                    WHERE p.id = x.id AND n.id = x.other AND n.globe = p.globe
+                        AND (p.valid_from > n.valid_until) IS NOT TRUE
+                        AND (n.valid_from > p.valid_until) IS NOT TRUE
+                    ORDER BY p.id;")
+                .expect("Failed to prepare select neighbour positions"),
+            insert_bad_position: conn
+                .prepare("INSERT OR IGNORE INTO bad_positions (id) VALUES (?1);")
+                .expect("Failed to prepare insert bad position"),
+            delete_bad_positions: conn
+                .prepare("DELETE FROM bad_positions;")
+                .expect("Failed to prepare delete bad positions"),
         }
     }
 }
-pub(crate) fn insert_base<'a>(
-    st: &mut Statements,
+/// Rows extracted from a dump entity, ready to be inserted
+///
+/// Extraction happens on the parsing threads, while the insertion happens on the single thread
+/// owning the DB connection.
+#[derive(Default)]
+pub(crate) struct Rows {
+    entity: Option<u64>,
+    /// Language and text
+    labels: Vec<(String, String)>,
+    descriptions: Vec<(String, String)>,
+    aliases: Vec<(String, String)>,
+    /// Site and page title
+    sitelinks: Vec<(String, String)>,
+    /// Latitude, longitude, precision and globe
+    position: Option<(u64, f64, f64, Option<f64>, u64, Interval)>,
+    /// All the coordinates, the position being one of them
+    coordinates: Vec<(u64, Coordinate)>,
+    natures: Vec<(u64, u64, Interval)>,
+    /// Of a place without borders, that is not ingested otherwise
+    borderless_natures: Vec<(u64, u64, Interval)>,
+    borderless_labels: Vec<(u64, String, String)>,
+    edges: Vec<(u64, u64, Interval)>,
+    /// Of each border statement, with its edge
+    edge_qualifiers: Vec<(u64, u64, EdgeQualifiers)>,
+    subclasses: Vec<(u64, u64)>,
+    /// Population, and the date it was counted
+    population: Option<(u64, u64, Option<i64>)>,
+    /// In km²
+    area: Option<(u64, f64)>,
+    countries: Vec<(u64, u64, Interval)>,
+    located_in: Vec<(u64, u64, Interval)>,
+    /// Property of each deprecated statement that was ignored, sorted
+    deprecated: Vec<String>,
+}
+impl Rows {
+    /// Deprecated statements are counted over the dump: those of a replaced entity already were
+    pub(crate) fn skip_deprecated_count(&mut self) {
+        self.deprecated.clear();
+    }
+}
+
+/// A coordinate (P625) of an entity
+struct Coordinate {
+    lat: f64,
+    lon: f64,
+    precision: Option<f64>,
+    globe: u64,
+    rank: Rank,
+    /// The part it applies to (P518)
+    part: Option<u64>,
+    /// One of the best by rank and part, that could be the position
+    candidate: bool,
+    interval: Interval,
+}
+
+/// Qualifiers and references of a border statement (P47)
+struct EdgeQualifiers {
+    /// The entity stating it
+    source: u64,
+    interval: Interval,
+    /// In km (P2043)
+    length: Option<f64>,
+    /// Statement is subject of (P805)
+    subject_of: Option<u64>,
+    references: usize,
+}
+
+pub(crate) fn insert(st: &mut Statements, rows: &Rows) {
+    rows.deprecated
+        .chunk_by(|a, b| a == b)
+        .for_each(|statements| {
+            st.count_deprecated
+                .execute((&statements[0], statements.len()))
+                .expect("Failed deprecated count");
+        });
+    if let Some(id) = rows.entity {
+        st.insert_entity.execute((id,)).expect("Failed base insert");
+        rows.labels.iter().for_each(|(lang, label)| {
+            st.insert_label
+                .execute((id, lang, label))
+                .expect("Failed label insert");
+        });
+        rows.descriptions.iter().for_each(|(lang, description)| {
+            st.insert_description
+                .execute((id, lang, description))
+                .expect("Failed description insert");
+        });
+        rows.aliases.iter().for_each(|(lang, alias)| {
+            st.insert_alias
+                .execute((id, lang, alias))
+                .expect("Failed alias insert");
+        });
+        rows.sitelinks.iter().for_each(|(site, title)| {
+            st.insert_sitelink
+                .execute((id, site, title))
+                .expect("Failed sitelink insert");
+        });
+    }
+    if let Some((id, lat, lon, precision, globe, interval)) = rows.position {
+        let (from, until) = timestamps(interval);
+        st.insert_position
+            .execute((id, lat, lon, precision, globe, from, until))
+            .expect("Failed insert");
+    }
+    rows.coordinates.iter().for_each(|(id, c)| {
+        let (from, until) = timestamps(c.interval);
+        let rank = match c.rank {
+            Rank::Preferred => "preferred",
+            _ => "normal",
+        };
+        st.insert_coordinate
+            .execute((
+                id,
+                c.lat,
+                c.lon,
+                c.precision,
+                c.globe,
+                rank,
+                c.part,
+                c.candidate,
+                from,
+                until,
+            ))
+            .expect("Failed coordinate insert");
+    });
+    rows.natures.iter().for_each(|&(id, nat, interval)| {
+        let (from, until) = timestamps(interval);
+        st.insert_nature
+            .execute((id, nat, from, until))
+            .expect("Failed nature insert");
+    });
+    rows.borderless_natures
+        .iter()
+        .for_each(|&(id, nat, interval)| {
+            let (from, until) = timestamps(interval);
+            st.insert_borderless_nature
+                .execute((id, nat, from, until))
+                .expect("Failed borderless nature insert");
+        });
+    rows.borderless_labels.iter().for_each(|(id, lang, label)| {
+        st.insert_borderless_label
+            .execute((id, lang, label))
+            .expect("Failed borderless label insert");
+    });
+    rows.edges.iter().for_each(|&(a, b, interval)| {
+        let (from, until) = timestamps(interval);
+        st.insert_edge
+            .execute((a, b, from, until))
+            .expect("Failed edge insert");
+    });
+    rows.edge_qualifiers.iter().for_each(|(a, b, q)| {
+        let (from, until) = timestamps(q.interval);
+        st.insert_edge_qualifiers
+            .execute((
+                a,
+                b,
+                q.source,
+                from,
+                until,
+                q.length,
+                q.subject_of,
+                q.references,
+            ))
+            .expect("Failed edge qualifiers insert");
+    });
+    rows.subclasses.iter().for_each(|&(id, parent_id)| {
+        st.insert_subclass
+            .execute((id, parent_id))
+            .expect("Failed subclass insert");
+    });
+    if let Some((id, population, date)) = rows.population {
+        st.insert_population
+            .execute((id, population, date))
+            .expect("Failed population insert");
+    }
+    if let Some((id, area)) = rows.area {
+        st.insert_area
+            .execute((id, area))
+            .expect("Failed area insert");
+    }
+    rows.countries.iter().for_each(|&(id, country, interval)| {
+        let (from, until) = timestamps(interval);
+        st.insert_country
+            .execute((id, country, from, until))
+            .expect("Failed country insert");
+    });
+    rows.located_in.iter().for_each(|&(id, parent, interval)| {
+        let (from, until) = timestamps(interval);
+        st.insert_located_in
+            .execute((id, parent, from, until))
+            .expect("Failed located in insert");
+    });
+}
+
+/// Validity bounds as stored in the DB, in seconds since the epoch
+fn timestamps(interval: Interval) -> (Option<i64>, Option<i64>) {
+    (
+        interval.start.map(|t| t.timestamp()),
+        interval.end.map(|t| t.timestamp()),
+    )
+}
+
+/// Remove everything known about an entity, before replacing it with a newer version
+///
+/// Only the borders stated by this entity are removed: edges also listed by a neighbour are kept,
+/// as they would be after a full ingestion.
+pub(crate) fn delete(st: &mut Statements, id: u64) {
+    st.delete_position
+        .execute((id,))
+        .expect("Failed position delete");
+    st.delete_coordinates
+        .execute((id,))
+        .expect("Failed coordinates delete");
+    st.delete_natures
+        .execute((id,))
+        .expect("Failed natures delete");
+    st.delete_borderless_natures
+        .execute((id,))
+        .expect("Failed borderless natures delete");
+    st.delete_borderless_labels
+        .execute((id,))
+        .expect("Failed borderless labels delete");
+    // Edges are derived from the remaining statements, so those go first
+    st.delete_edge_qualifiers
+        .execute((id,))
+        .expect("Failed edge qualifiers delete");
+    st.delete_edges.execute((id,)).expect("Failed edges delete");
+    st.delete_subclass
+        .execute((id,))
+        .expect("Failed subclass delete");
+    st.delete_population
+        .execute((id,))
+        .expect("Failed population delete");
+    st.delete_area.execute((id,)).expect("Failed area delete");
+    st.delete_countries
+        .execute((id,))
+        .expect("Failed countries delete");
+    st.delete_located_in
+        .execute((id,))
+        .expect("Failed located in delete");
+    st.delete_labels
+        .execute((id,))
+        .expect("Failed labels delete");
+    st.delete_descriptions
+        .execute((id,))
+        .expect("Failed descriptions delete");
+    st.delete_aliases
+        .execute((id,))
+        .expect("Failed aliases delete");
+    st.delete_sitelinks
+        .execute((id,))
+        .expect("Failed sitelinks delete");
+    // Last, for foreign keys
+    st.delete_entity
+        .execute((id,))
+        .expect("Failed entity delete");
+}
+
+/// Among the candidate coordinates of an entity, make the closest to the median of its neighbours
+/// its position: the other ones are often of a part far from the rest of the place. Only those
+/// valid at the given date are considered, unless none of them is.
+pub(crate) fn pick_positions(
+    conn: &rusqlite::Connection,
+    as_of: DateTime<Utc>,
+) -> Result<(), Box<dyn Error>> {
+    type Candidate = (u64, f64, f64, Option<f64>, u64);
+    let mut candidates = conn.prepare(
+        "SELECT id, lat, lon, precision, globe,
+                ?1 BETWEEN IFNULL(valid_from, ?1) AND IFNULL(valid_until, ?1)
+            FROM coordinates WHERE candidate
+            AND id IN (SELECT id FROM coordinates WHERE candidate GROUP BY id HAVING COUNT(*) > 1)
+            ORDER BY id;",
+    )?;
+    let candidates = candidates
+        .query_map((as_of.timestamp(),), |row| {
+            Ok((
+                (
+                    row.get::<_, u64>(0)?,
+                    row.get::<_, f64>(1)?,
+                    row.get::<_, f64>(2)?,
+                    row.get::<_, Option<f64>>(3)?,
+                    row.get::<_, u64>(4)?,
+                ),
+                row.get::<_, bool>(5)?,
+            ))
+        })?
+        .collect::<Result<Vec<(Candidate, bool)>, _>>()?;
+    let mut neighbours = conn.prepare(
+        "SELECT lat, lon FROM positions WHERE id IN
+            (SELECT b FROM edges WHERE a = ?1 UNION SELECT a FROM edges WHERE b = ?1);",
+    )?;
+    let mut update = conn.prepare(
+        "UPDATE positions SET lat = ?2, lon = ?3, precision = ?4, globe = ?5 WHERE id = ?1;",
+    )?;
+    let mut picked = 0;
+    for coordinates in candidates.chunk_by(|a, b| a.0.0 == b.0.0) {
+        let id = coordinates[0].0.0;
+        let valid = coordinates
+            .iter()
+            .filter(|c| c.1)
+            .map(|c| c.0)
+            .collect::<Vec<_>>();
+        let coordinates = if valid.is_empty() {
+            coordinates.iter().map(|c| c.0).collect()
+        } else {
+            valid
+        };
+        let (mut lats, mut lons): (Vec<f64>, Vec<f64>) = neighbours
+            .query_map((id,), |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(f64, f64)>, _>>()?
+            .into_iter()
+            .unzip();
+        // Keep the first one
+        if coordinates.len() == 1 || lats.is_empty() {
+            update.execute(coordinates[0])?;
+            continue;
+        }
+        let (lat, lon) = (median(&mut lats), median(&mut lons));
+        let distance = |c: &&Candidate| {
+            // Equirectangular approximation, good enough to compare
+            let dlon = (c.2 - lon + 540.0).rem_euclid(360.0) - 180.0;
+            (c.1 - lat).powi(2) + (dlon * lat.to_radians().cos()).powi(2)
+        };
+        let closest = coordinates
+            .iter()
+            .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+            .expect("Should have candidates");
+        update.execute(*closest)?;
+        picked += 1;
+    }
+    println!("positions picked by their neighbours: {picked}");
+    Ok(())
+}
+
+pub(crate) fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+
+pub(crate) fn extract_base<'a>(
+    rows: &mut Rows,
     item: &Element<'a>,
+    config: &Config,
 ) -> Result<(), Box<dyn Error>> {
-    let label_en = label(&item.labels, "en").unwrap_or_else(|| label_or_empty(&item.labels, "mul"));
-    let label_fr = label_or_empty(&item.labels, "fr");
+    rows.labels = kept_texts(&item.labels, config);
+    rows.descriptions = kept_texts(&item.descriptions, config);
+    rows.aliases = item
+        .aliases
+        .iter()
+        .filter(|(lang, _)| config.keeps_label(lang))
+        .flat_map(|(lang, aliases)| {
+            aliases
+                .iter()
+                .map(|l| (lang.to_string(), l.value.to_string()))
+        })
+        .collect();
+    rows.sitelinks = config
+        .sitelinks
+        .iter()
+        .filter_map(|site| {
+            let sitelink = item.sitelinks.get(site.as_str())?;
+            Some((site.clone(), sitelink.title.to_string()))
+        })
+        .collect();
+
+    rows.deprecated = item.deprecated.iter().map(|p| p.to_string()).collect();
 
     let id = int_id_faillible(item.id)?;
-    st.insert_entity
-        .execute((id, label_en, label_fr))
-        .expect("Failed base insert");
+    rows.entity = Some(id);
     Ok(())
 }
-pub(crate) fn insert<'a>(st: &mut Statements, item: &Element<'a>) {
-    let natures = item
-        .claims
+/// Labels or descriptions in the kept languages
+fn kept_texts(texts: &Labels, config: &Config) -> Vec<(String, String)> {
+    texts
+        .iter()
+        .filter(|(lang, _)| config.keeps_label(lang))
+        .map(|(lang, l)| (lang.to_string(), l.value.to_string()))
+        .collect()
+}
+
+/// Natures of the entity with their validity, subject roles included
+fn item_natures(item: &Element) -> HashSet<(u64, Interval)> {
+    item.claims
         .get(NATURE_CLAIM)
-        .unwrap_or_else(|| {
-            panic!("No nature for {}", item.id);
+        .into_iter()
+        .flatten()
+        .flat_map(|nat| {
+            let interval = claim_interval(nat);
+            claim_and_roles(nat).map(move |n| (n, interval))
         })
-        .iter()
-        .filter(|nat| claim_still_valid(nat))
-        .flat_map(|nat| claim_and_roles(nat))
-        .collect::<HashSet<_>>()
-        .into_iter();
-    // We should not reach this code without an existing position
-    let position = item
+        .collect()
+}
+
+/// Only the natures and labels of a place without borders, which can still be the neighbour of
+/// others
+pub(crate) fn extract_borderless<'a>(rows: &mut Rows, item: &Element<'a>, config: &Config) {
+    let id = int_id_faillible(item.id).expect("Incorrect item id");
+    rows.borderless_labels.extend(
+        kept_texts(&item.labels, config)
+            .into_iter()
+            .map(|(lang, label)| (id, lang, label)),
+    );
+    rows.borderless_natures.extend(
+        item_natures(item)
+            .into_iter()
+            .map(|(nat, interval)| (id, nat, interval)),
+    );
+}
+
+/// Extract the entity with its whole history: natures and edges are kept with their validity.
+/// Mandatory claims are configurable, so entities without a nature or a position are skipped
+pub(crate) fn extract<'a>(rows: &mut Rows, item: &Element<'a>, config: &Config) {
+    if !item.claims.contains_key(NATURE_CLAIM) {
+        return;
+    }
+    let natures = item_natures(item).into_iter();
+    let mut coordinates: Vec<Coordinate> = item
         .claims
         .get(POSITION_CLAIM)
-        .expect("Should have a position")
-        .iter()
+        .into_iter()
+        .flatten()
         .filter_map(|pos| {
-            if let Snak::GlobeCoordinate { ref value } = pos.mainsnak {
-                Some(value)
-            } else {
-                None
-            }
+            let Snak::GlobeCoordinate { ref value } = pos.mainsnak else {
+                return None;
+            };
+            let globe = value
+                .globe
+                .as_deref()
+                .and_then(|uri| uri.rsplit('/').next())
+                .unwrap_or(EARTH);
+            let globe = match int_id_faillible(globe) {
+                Ok(globe) => globe,
+                Err(e) => {
+                    println!("Warning: invalid globe for {}: {e}", item.id);
+                    return None;
+                }
+            };
+            let part = pos
+                .qualifiers
+                .as_ref()
+                .and_then(|quals| quals.get(APPLIES_TO_PART_CLAIM))
+                .and_then(|parts| {
+                    parts.iter().find_map(|part| match part {
+                        Snak::Item { value } => int_id_faillible(value.id).ok(),
+                        _ => None,
+                    })
+                });
+            Some(Coordinate {
+                lat: value.latitude,
+                lon: value.longitude,
+                precision: value.precision,
+                globe,
+                rank: pos.rank,
+                part,
+                candidate: false,
+                interval: claim_interval(pos),
+            })
         })
-        .next();
+        .collect();
+    // The best ranked ones, and among them those of a representative part, or else those that
+    // aren't of a specific part; neighbours decide between the remaining candidates, once they
+    // are all ingested
+    let part_score = |c: &Coordinate| match c.part {
+        Some(part) if config.coordinate_parts.contains(&part) => 2,
+        Some(_) => 0,
+        None => 1,
+    };
+    let best = coordinates
+        .iter()
+        .map(|c| (c.rank == Rank::Preferred, part_score(c)))
+        .max();
     // Ignore item with no position
-    let position = match position {
-        Some(pos) => pos,
-        None => return,
+    let Some(best) = best else {
+        return;
     };
+    coordinates.iter_mut().for_each(|c| {
+        c.candidate = (c.rank == Rank::Preferred, part_score(c)) == best;
+    });
+    // Until neighbours decide, the first one valid at the date of the dump
+    let position = coordinates
+        .iter()
+        .find(|c| c.candidate && c.interval.contains(config.as_of()))
+        .or_else(|| coordinates.iter().find(|c| c.candidate))
+        .expect("Should have a candidate");
     let connections = item
         .claims
         .get(SHARES_BORDER_WITH_CLAIM)
-        .expect("Should have connections")
-        .iter()
+        .into_iter()
+        .flatten()
         .filter_map(|pos| {
             //dbg!(&pos.mainsnak);
             if let Snak::Item { ref value } = pos.mainsnak {
                 match int_id_faillible(value.id) {
-                    Ok(a) => Some(a),
+                    Ok(a) => Some((a, pos)),
                     Err(e) => {
                         println!("Warning: invalid shares border with: {e}");
                         None
@@ -224,42 +1298,140 @@ pub(crate) fn insert<'a>(st: &mut Statements, item: &Element<'a>) {
             }
         });
     let id = int_id_faillible(item.id).expect("Incorrect item id");
-    st.insert_position
-        .execute((id, position.latitude, position.longitude))
-        .expect("Failed insert");
-    natures.for_each(|nat| {
-        st.insert_nature
-            .execute((id, nat))
-            .expect("Failed nature insert");
-    });
-    connections.for_each(|edge| {
+    rows.position = Some((
+        id,
+        position.lat,
+        position.lon,
+        position.precision,
+        position.globe,
+        entity_interval(item),
+    ));
+    rows.coordinates
+        .extend(coordinates.into_iter().map(|c| (id, c)));
+    rows.natures
+        .extend(natures.map(|(nat, interval)| (id, nat, interval)));
+    for (edge, claim) in connections {
         let mut items = [id, edge];
         items.sort();
-        st.insert_edge
-            .execute((items[0], items[1]))
-            .expect("Failed edge insert");
-    });
+        let interval = claim_interval(claim);
+        rows.edges.push((items[0], items[1], interval));
+        let qualifiers = |property| {
+            claim
+                .qualifiers
+                .as_ref()
+                .and_then(|quals| quals.get(property))
+                .into_iter()
+                .flatten()
+        };
+        let length = qualifiers(LENGTH_CLAIM).find_map(|length| {
+            let Snak::Quantity { value } = length else {
+                return None;
+            };
+            let length: f64 = value.amount.parse().ok()?;
+            let unit = value.unit.rsplit('/').next()?;
+            Some(length * km_per_unit(unit)?)
+        });
+        let subject_of = qualifiers(SUBJECT_OF_CLAIM).find_map(|subject| match subject {
+            Snak::Item { value } => int_id_faillible(value.id).ok(),
+            _ => None,
+        });
+        rows.edge_qualifiers.push((
+            items[0],
+            items[1],
+            EdgeQualifiers {
+                source: id,
+                interval,
+                length,
+                subject_of,
+                references: claim.references.len(),
+            },
+        ));
+    }
 }
 
-fn label<'a>(labels: &Labels<'a>, lang: &str) -> Option<String> {
-    labels.get(lang).map(|l| l.value.to_string())
+/// Optional attributes of places, to size, colour or filter them
+pub(crate) fn extract_attributes<'a>(rows: &mut Rows, item: &Element<'a>) {
+    let id = int_id_faillible(item.id).expect("Incorrect item id");
+    let claims = |claim| item.claims.get(claim).into_iter().flatten();
+    // The latest count; undated ones only if there is nothing else
+    rows.population = claims(POPULATION_CLAIM)
+        .filter_map(|claim| {
+            let Snak::Quantity { ref value } = claim.mainsnak else {
+                return None;
+            };
+            let population: f64 = value.amount.parse().ok()?;
+            Some((population as u64, claim_point_in_time(claim)))
+        })
+        .max_by_key(|&(_, date)| date)
+        .map(|(population, date)| (id, population, date.map(|d| d.timestamp())));
+    rows.area = claims(AREA_CLAIM)
+        .find_map(|claim| {
+            let Snak::Quantity { ref value } = claim.mainsnak else {
+                return None;
+            };
+            let area: f64 = value.amount.parse().ok()?;
+            let unit = value.unit.rsplit('/').next()?;
+            Some(area * km2_per_unit(unit)?)
+        })
+        .map(|area| (id, area));
+    let items = |claim| {
+        claims(claim).filter_map(move |claim| {
+            let Snak::Item { ref value } = claim.mainsnak else {
+                return None;
+            };
+            match int_id_faillible(value.id) {
+                Ok(item) => Some((id, item, claim_interval(claim))),
+                Err(e) => {
+                    println!("Warning: invalid {}: {e}", value.id);
+                    None
+                }
+            }
+        })
+    };
+    rows.countries.extend(items(COUNTRY_CLAIM));
+    rows.located_in.extend(items(LOCATED_IN_CLAIM));
 }
-fn label_or_empty<'a>(labels: &Labels<'a>, lang: &str) -> String {
-    label(labels, lang).unwrap_or_default()
+
+fn km2_per_unit(unit: &str) -> Option<f64> {
+    match unit {
+        // square kilometre
+        "Q712226" => Some(1.0),
+        // square metre
+        "Q25343" => Some(1e-6),
+        // hectare
+        "Q35852" => Some(0.01),
+        // square mile
+        "Q232291" => Some(2.589_988_110_336),
+        _ => None,
+    }
 }
 
-pub(crate) fn insert_subclass<'a>(
-    st: &mut Statements,
+fn km_per_unit(unit: &str) -> Option<f64> {
+    match unit {
+        // kilometre
+        "Q828224" => Some(1.0),
+        // metre
+        "Q11573" => Some(1e-3),
+        // mile
+        "Q253276" => Some(1.609_344),
+        _ => None,
+    }
+}
+
+pub(crate) fn extract_subclass<'a>(
+    rows: &mut Rows,
     item: &Element<'a>,
-    banned_parents: &HashSet<u64>,
+    banned_parents: &BTreeSet<u64>,
+    as_of: DateTime<Utc>,
 ) {
-    item.claims
+    let subclasses = item
+        .claims
         .get(SUBCLASS_OF_CLAIM)
         .unwrap_or_else(|| {
             panic!("No subclass for {}", item.id);
         })
         .iter()
-        .filter(|claim| claim_still_valid(claim))
+        .filter(|claim| claim_still_valid(claim, as_of))
         .filter_map(|pos| {
             if let Snak::Item { ref value } = pos.mainsnak {
                 Some((item.id, value.id))
@@ -282,12 +1454,8 @@ pub(crate) fn insert_subclass<'a>(
                 }
             }
         })
-        .filter(|(_, parent)| !banned_parents.contains(parent))
-        .for_each(|(id, parent_id)| {
-            st.insert_subclass
-                .execute((id, parent_id))
-                .expect("Failed subclass insert");
-        });
+        .filter(|(_, parent)| !banned_parents.contains(parent));
+    rows.subclasses.extend(subclasses);
 }
 
 pub(crate) fn int_id_faillible(id: &str) -> Result<u64, String> {
@@ -302,3 +1470,96 @@ pub(crate) fn int_id_faillible(id: &str) -> Result<u64, String> {
 pub(crate) fn int_id(id: &str) -> u64 {
     int_id_faillible(id).unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process_line;
+
+    use chrono::NaiveDate;
+
+    /// A place of nature Q484170 at each of `coordinates`, valid until the given date if any,
+    /// sharing a border with each of `borders`
+    fn place(id: &str, coordinates: &[(f64, f64, Option<&str>)], borders: &[&str]) -> String {
+        let item = |id: &str| {
+            serde_json::json!({
+                "datatype": "wikibase-item",
+                "datavalue": {"value": {"id": id}}
+            })
+        };
+        let coordinate = |&(latitude, longitude, until): &(f64, f64, Option<&str>)| {
+            let qualifiers = until.map(|time| {
+                serde_json::json!({"P582": [{
+                    "datatype": "time",
+                    "datavalue": {"value": {"time": time, "precision": 11}}
+                }]})
+            });
+            serde_json::json!({
+                "mainsnak": {
+                    "datatype": "globe-coordinate",
+                    "datavalue": {"value": {"latitude": latitude, "longitude": longitude}}
+                },
+                "qualifiers": qualifiers
+            })
+        };
+        serde_json::json!({
+            "id": id,
+            "labels": {"en": {"value": format!("Place {id}")}},
+            "claims": {
+                "P31": [{"mainsnak": item("Q484170")}],
+                "P625": coordinates.iter().map(coordinate).collect::<Vec<_>>(),
+                "P47": borders.iter().map(|b| serde_json::json!({"mainsnak": item(b)})).collect::<Vec<_>>()
+            }
+        })
+        .to_string()
+    }
+
+    fn position(conn: &rusqlite::Connection, id: u64) -> (f64, f64) {
+        conn.query_row(
+            "SELECT lat, lon FROM positions WHERE id = ?1;",
+            (id,),
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .expect("a position")
+    }
+
+    #[test]
+    fn positions_valid_at_the_date() {
+        let mut config = Config {
+            as_of: NaiveDate::from_ymd_opt(2025, 6, 1),
+            ..Config::default()
+        };
+        let mut conn = rusqlite::Connection::open_in_memory().expect("in memory DB");
+        create_tables(&mut conn, &config.banned_generic_categories).expect("tables");
+        let mut statements = Statements::new(&conn);
+        // Both neighbours are close to the expired position of Q1
+        for (i, line) in [
+            place(
+                "Q1",
+                &[
+                    (10.0, 10.0, Some("+2000-01-01T00:00:00Z")),
+                    (45.0, 2.0, None),
+                ],
+                &["Q2", "Q3"],
+            ),
+            place("Q2", &[(10.1, 10.1, None)], &["Q1"]),
+            place("Q3", &[(10.2, 10.2, None)], &["Q1"]),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let rows = process_line(&config, i, line)
+                .expect("matching")
+                .expect("valid");
+            insert(&mut statements, &rows);
+        }
+        assert_eq!(position(&conn, 1), (45.0, 2.0));
+        pick_positions(&conn, config.as_of()).expect("picked");
+        assert_eq!(position(&conn, 1), (45.0, 2.0));
+
+        // Both were valid then: the closest to the neighbours is picked
+        config.as_of = NaiveDate::from_ymd_opt(1990, 1, 1);
+        pick_positions(&conn, config.as_of()).expect("picked");
+        assert_eq!(position(&conn, 1), (10.0, 10.0));
+    }
+}
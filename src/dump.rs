@@ -0,0 +1,206 @@
+// Opening of Wikidata dumps, whatever their compression
+//
+// Wikimedia publishes both bz2 and gz dumps; we also want to accept recompressed (zstd) or already
+// decompressed copies, as well as reading from a pipe.
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use chrono::{DateTime, NaiveDate, Utc};
+
+use flate2::read::MultiGzDecoder;
+
+use crate::bz2::{BlockStart, BlockStarts, MultiBzDecoder};
+
+const BZ2_MAGIC: &[u8] = b"BZh";
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, PartialEq)]
+enum Format {
+    Bz2,
+    Gzip,
+    Zstd,
+    Uncompressed,
+}
+
+impl Format {
+    /// From the first bytes of the input, returned to be read again; a pipe can return fewer of
+    /// them at a time than needed
+    fn detect<R: Read>(filename: &str, input: &mut R) -> Result<(Self, Vec<u8>), String> {
+        let mut start = Vec::with_capacity(ZSTD_MAGIC.len());
+        input
+            .take(ZSTD_MAGIC.len() as u64)
+            .read_to_end(&mut start)
+            .map_err(|e| format!("Cannot read {filename}: {e}"))?;
+        let format = if start.starts_with(BZ2_MAGIC) {
+            Format::Bz2
+        } else if start.starts_with(GZIP_MAGIC) {
+            Format::Gzip
+        } else if start.starts_with(ZSTD_MAGIC) {
+            Format::Zstd
+        } else {
+            Format::Uncompressed
+        };
+        println!("Reading {filename} as {format:?}");
+        Ok((format, start))
+    }
+}
+
+/// Date the dump was generated: from its name (e.g. wikidata-20250601-all.json.bz2), or its
+/// modification time, as set by `curl --remote-time`
+pub(crate) fn date(filename: &str) -> Option<NaiveDate> {
+    if filename == "-" {
+        return None;
+    }
+    let name = Path::new(filename).file_name()?.to_str()?;
+    name.as_bytes()
+        .windows(8)
+        .filter(|w| w.iter().all(u8::is_ascii_digit))
+        .find_map(|w| NaiveDate::parse_from_str(str::from_utf8(w).ok()?, "%Y%m%d").ok())
+        .or_else(|| {
+            let modified = fs::metadata(filename).ok()?.modified().ok()?;
+            Some(DateTime::<Utc>::from(modified).date_naive())
+        })
+}
+
+/// Whether both names are of the same dump file, maybe through different paths
+pub(crate) fn same(a: &str, b: &str) -> bool {
+    a == b || matches!((fs::canonicalize(a), fs::canonicalize(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+/// Open a dump file, `-` meaning stdin, and return its decompressed content, starting at the
+/// given decompressed byte offset
+pub(crate) fn open(filename: &str, offset: u64) -> Result<Box<dyn Read + Send>, String> {
+    Ok(open_at(filename, offset, None)?.0)
+}
+
+/// Same as `open`, restarting a bz2 file at the given block instead of decompressing everything
+/// before it. The start of the following blocks are returned for bz2 files, to restart from them
+/// later; gzip and zstd files, as well as stdin, can't be restarted that way.
+pub(crate) fn open_at(
+    filename: &str,
+    offset: u64,
+    block: Option<BlockStart>,
+) -> Result<(Box<dyn Read + Send>, Option<BlockStarts>), String> {
+    if filename == "-" {
+        return Ok((open_stream(filename, io::stdin(), offset)?, None));
+    }
+    let mut file = File::open(filename).map_err(|e| format!("Cannot open {filename}: {e}"))?;
+    let (format, _) = Format::detect(filename, &mut file)?;
+    file.rewind()
+        .map_err(|e| format!("Cannot seek back to the start of {filename}: {e}"))?;
+    let mut file = BufReader::with_capacity(1024 * 1024, file);
+    match format {
+        Format::Uncompressed => {
+            file.seek(SeekFrom::Start(offset))
+                .map_err(|e| format!("Cannot seek to offset {offset} in {filename}: {e}"))?;
+            Ok((decoder(Format::Uncompressed, file)?, None))
+        }
+        Format::Bz2 => {
+            let block = block.filter(|block| block.offset <= offset);
+            let start = block.map_or(0, |block| block.bit / 8);
+            file.seek(SeekFrom::Start(start))
+                .map_err(|e| format!("Cannot seek to offset {start} in {filename}: {e}"))?;
+            let (decoder, starts) = MultiBzDecoder::resumable(file, block);
+            let to_skip = offset - block.map_or(0, |block| block.offset);
+            Ok((skip(filename, Box::new(decoder), to_skip)?, Some(starts)))
+        }
+        format => Ok((skip(filename, decoder(format, file)?, offset)?, None)),
+    }
+}
+
+/// Open an input that can't be seeked, like a pipe
+fn open_stream<R: Read + Send + 'static>(
+    filename: &str,
+    mut input: R,
+    offset: u64,
+) -> Result<Box<dyn Read + Send>, String> {
+    let (format, start) = Format::detect(filename, &mut input)?;
+    let input = BufReader::new(io::Cursor::new(start).chain(input));
+    skip(filename, decoder(format, input)?, offset)
+}
+
+fn skip(
+    filename: &str,
+    mut input: Box<dyn Read + Send>,
+    offset: u64,
+) -> Result<Box<dyn Read + Send>, String> {
+    if offset > 0 {
+        // Compressed streams can't be seeked within blocks; decompressing and throwing away is
+        // still much faster than parsing
+        let skipped = io::copy(&mut (&mut input).take(offset), &mut io::sink())
+            .map_err(|e| format!("Cannot skip to offset {offset} in {filename}: {e}"))?;
+        if skipped != offset {
+            return Err(format!(
+                "{filename} is shorter than the offset to resume at ({offset})"
+            ));
+        }
+    }
+    Ok(input)
+}
+
+fn decoder<R: BufRead + Send + 'static>(
+    format: Format,
+    input: R,
+) -> Result<Box<dyn Read + Send>, String> {
+    Ok(match format {
+        Format::Bz2 => Box::new(MultiBzDecoder::new(input)),
+        Format::Gzip => Box::new(MultiGzDecoder::new(input)),
+        Format::Zstd => Box::new(
+            zstd::Decoder::with_buffer(input)
+                .map_err(|e| format!("Cannot initialize zstd decoder: {e}"))?,
+        ),
+        Format::Uncompressed => Box::new(input),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const DUMP: &str = "[\n{\"id\": \"Q1\"},\n{\"id\": \"Q2\"}\n]\n";
+
+    /// A pipe returning a single byte at a time
+    struct Trickle(io::Cursor<Vec<u8>>);
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(1);
+            self.0.read(&mut buf[..n])
+        }
+    }
+
+    fn read(input: Vec<u8>, offset: u64) -> String {
+        let mut out = String::new();
+        open_stream("-", Trickle(io::Cursor::new(input)), offset)
+            .expect("valid dump")
+            .read_to_string(&mut out)
+            .expect("valid dump");
+        out
+    }
+
+    #[test]
+    fn formats() {
+        let mut bz2 = bzip2::write::BzEncoder::new(vec![], bzip2::Compression::fast());
+        bz2.write_all(DUMP.as_bytes()).expect("write to vec");
+        let mut gzip = flate2::write::GzEncoder::new(vec![], flate2::Compression::fast());
+        gzip.write_all(DUMP.as_bytes()).expect("write to vec");
+        let zstd = zstd::encode_all(DUMP.as_bytes(), 0).expect("write to vec");
+        for input in [
+            bz2.finish().expect("write to vec"),
+            gzip.finish().expect("write to vec"),
+            zstd,
+            DUMP.as_bytes().to_vec(),
+        ] {
+            assert_eq!(read(input.clone(), 0), DUMP);
+            assert_eq!(read(input, 2), &DUMP[2..]);
+        }
+    }
+
+    #[test]
+    fn shorter_than_magic() {
+        assert_eq!(read(b"[]".to_vec(), 0), "[]");
+        assert_eq!(read(vec![], 0), "");
+    }
+}
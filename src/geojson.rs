@@ -1,70 +1,199 @@
-use crate::db::Statements;
-use crate::int_id;
+use crate::db::{Statements, int_id, int_id_faillible};
+use crate::report::{store_bad_positions, store_distant_edges};
+use crate::{Config, EARTH};
 
 use std::borrow::Cow;
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::error::Error;
 use std::fs::File;
+use std::path::{Path, PathBuf};
 
+use chrono::DateTime;
 use indexmap::IndexMap;
 use serde::ser::{self, SerializeSeq};
-use serde::{Serialize, Serializer};
+use serde::{Deserialize, Serialize, Serializer};
 
 pub(crate) fn generate(
     statements: &mut Statements,
-    banned_generic_categories: &HashSet<u64>,
+    config: &Config,
+    output: &Path,
 ) -> Result<(), Box<dyn Error>> {
+    if config.exclude_bad_positions {
+        store_bad_positions(statements)?;
+    }
+    for globe in &config.globes {
+        generate_globe(statements, config, globe, &globe_output(output, globe))?;
+    }
+    Ok(())
+}
+
+/// Places on other globes than Earth are generated separately, as they can't share borders
+fn globe_output(output: &Path, globe: &str) -> PathBuf {
+    if globe == EARTH {
+        output.to_path_buf()
+    } else {
+        output.join(globe)
+    }
+}
+
+fn generate_globe(
+    statements: &mut Statements,
+    config: &Config,
+    globe: &str,
+    output: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let globe_int = int_id_faillible(globe).map_err(|e| format!("Invalid globe: {e}"))?;
     // Get top N categories, and fetch their name
-    let top = &mut statements.top_categories_by_edges;
-    let rows = top.query_map([], |row| row.get(0))?;
     let mut categories = IndexMap::new();
-    for x in rows {
-        let id_int: u64 = x?;
+    for id_int in top_categories(statements, config, globe_int)? {
         let id: String = format!("Q{id_int}");
-        if !banned_generic_categories.contains(&id_int) {
-            // Make sure we have the description of this category.
-            let labels = fetch_missing_entity_name(
-                &mut statements.select_entity,
-                &mut statements.insert_entity,
-                id_int,
-            )?;
-            categories.insert(id, labels);
-        }
+        // Make sure we have the description of this category.
+        let (labels, descriptions) = fetch_missing_entity_name(statements, config, id_int)?;
+        categories.insert(
+            id,
+            IndexEntry {
+                labels: select_labels(config, labels),
+                descriptions: select_descriptions(config, descriptions),
+                globe: globe.to_string(),
+            },
+        );
     }
 
-    std::fs::create_dir_all("web/geojson")?;
-    let idx = File::create_new("web/geojson/index.json")?;
+    std::fs::create_dir_all(output)?;
+    let idx = File::create_new(output.join("index.json"))?;
     serde_json::to_writer(idx, &categories)?;
 
-    let select_nodes = &mut statements.select_entities_category;
-    let select_links = &mut statements.select_edges_category;
     for id in categories.keys() {
-        let id_int = int_id(id);
-        let nodes = File::create_new(format!("web/geojson/{id}-nodes.geojson"))?;
-        let entities = select_nodes.query((id_int,))?;
-        let geo = GeoJsonRootNodes::new(RefCell::new(entities));
-        serde_json::to_writer(nodes, &geo)?;
-        let links = File::create_new(format!("web/geojson/{id}-links.geojson"))?;
-        let edges = select_links.query((id_int,))?;
-        let geo = GeoJsonRootEdges::new(edges);
+        write_category(statements, config, int_id(id), globe_int, output)?;
+    }
+    Ok(())
+}
+
+/// The generated categories of a globe, from the most connected one
+pub(crate) fn top_categories(
+    statements: &mut Statements,
+    config: &Config,
+    globe: u64,
+) -> Result<Vec<u64>, Box<dyn Error>> {
+    let ranking = &config.ranking;
+    let rows = statements.top_categories_by_edges.query_map(
+        (
+            ranking.min_edges,
+            ranking.min_edges_per_entity,
+            ranking.max_categories,
+            config.as_of().timestamp(),
+            globe,
+            config.referenced_edges_only,
+        ),
+        |row| row.get(0),
+    )?;
+    let top = rows.collect::<Result<Vec<u64>, _>>()?;
+    Ok(top
+        .into_iter()
+        .filter(|id| !config.banned_generic_categories.contains(id))
+        .collect())
+}
+
+/// Generate the files of a single category, even if it's not one of the top ones
+pub(crate) fn export(
+    statements: &mut Statements,
+    config: &Config,
+    id: u64,
+    output: &Path,
+) -> Result<(), Box<dyn Error>> {
+    if config.exclude_bad_positions {
+        store_bad_positions(statements)?;
+    }
+    for globe in &config.globes {
+        let globe_int = int_id_faillible(globe).map_err(|e| format!("Invalid globe: {e}"))?;
+        let output = globe_output(output, globe);
+        std::fs::create_dir_all(&output)?;
+        write_category(statements, config, id, globe_int, &output)?;
+    }
+    Ok(())
+}
+
+fn write_category(
+    statements: &mut Statements,
+    config: &Config,
+    id_int: u64,
+    globe: u64,
+    output: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let id = format!("Q{id_int}");
+    let date = config.as_of().timestamp();
+    if config.exclude_distant_edges {
+        store_distant_edges(statements, config, id_int, globe)?;
+    }
+    let nodes = File::create_new(output.join(format!("{id}-nodes.geojson")))?;
+    let entities = statements.select_entities_category.query((
+        id_int,
+        date,
+        globe,
+        config.exclude_bad_positions,
+        config.all_periods,
+    ))?;
+    let geo = GeoJsonRootNodes::new(RefCell::new(entities), config);
+    serde_json::to_writer(nodes, &geo)?;
+    let links = File::create_new(output.join(format!("{id}-links.geojson")))?;
+    let (referenced, distant, bad) = (
+        config.referenced_edges_only,
+        config.exclude_distant_edges,
+        config.exclude_bad_positions,
+    );
+    let edges = statements.select_edges_category.query((
+        id_int,
+        date,
+        globe,
+        referenced,
+        distant,
+        bad,
+        config.edge_features,
+        config.all_periods,
+    ))?;
+    if config.edge_features {
+        let geo = GeoJsonRootEdges::new(RowsEdgeFeatures {
+            r: RefCell::new(edges),
+            config,
+        });
+        serde_json::to_writer(links, &geo)?;
+    } else {
+        let geo = GeoJsonRootEdges::new(RowsEdges {
+            r: RefCell::new(edges),
+            config,
+        });
         serde_json::to_writer(links, &geo)?;
     }
     Ok(())
 }
 
-fn fetch_missing_entity_name<'st>(
-    select_entity: &mut rusqlite::Statement<'st>,
-    insert_entity: &mut rusqlite::Statement<'st>,
+/// Labels and descriptions of an entity
+type Texts = (BTreeMap<String, String>, BTreeMap<String, String>);
+
+fn fetch_missing_entity_name(
+    statements: &mut Statements,
+    config: &Config,
     id: u64,
-) -> Result<HashMap<&'static str, String>, Box<dyn Error>> {
-    match select_entity.query_one((id,), |row| Ok((row.get(0), row.get(1)))) {
-        Err(rusqlite::Error::QueryReturnedNoRows) => {}
-        Ok((en, fr)) => {
-            return Ok(HashMap::from([("en", en?), ("fr", fr?)]));
+) -> Result<Texts, Box<dyn Error>> {
+    let mut rows = statements
+        .select_labels
+        .query((id,))
+        .map_err(|e| format!("Cannot fetch: {e}"))?;
+    let mut known = None;
+    while let Some(row) = rows.next()? {
+        let labels = known.get_or_insert_with(BTreeMap::new);
+        if let Some(lang) = row.get::<_, Option<String>>(0)? {
+            labels.insert(lang, row.get(1)?);
         }
-        Err(e) => return Err(format!("Cannot fetch: {e}").into()),
-    };
+    }
+    if let Some(labels) = known {
+        let descriptions = statements
+            .select_descriptions
+            .query_map((id,), |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        return Ok((labels, descriptions));
+    }
     // Category is not present - fetch its name responsibly from the wikidata API, and cache the
     // result
     let resp = reqwest::blocking::Client::builder()
@@ -73,15 +202,97 @@ fn fetch_missing_entity_name<'st>(
         .cookie_store(true)
         .build()?
         .get(format!(
-            "https://www.wikidata.org/w/rest.php/wikibase/v1/entities/items/Q{id}/labels"
+            "https://www.wikidata.org/w/rest.php/wikibase/v1/entities/items/Q{id}?_fields=labels,descriptions,aliases"
         ))
         .send()?
         .bytes()?;
-    let names: LabelsQuery = serde_json::from_slice(&resp)?;
-    let label_en = label_q(&names, "en").unwrap_or_else(|| label_or_empty_q(&names, "mul"));
-    let label_fr = label_or_empty_q(&names, "fr");
-    insert_entity.execute((id, &label_en, &label_fr))?;
-    Ok(HashMap::from([("en", label_en), ("fr", label_fr)]))
+    let item: ItemQuery = serde_json::from_slice(&resp)?;
+    let texts = |texts: LabelsQuery| -> BTreeMap<String, String> {
+        texts
+            .into_iter()
+            .filter(|(lang, _)| config.keeps_label(lang))
+            .map(|(lang, text)| (lang.to_string(), text.into_owned()))
+            .collect()
+    };
+    let labels = texts(item.labels);
+    let descriptions = texts(item.descriptions);
+    statements.insert_entity.execute((id,))?;
+    for (lang, label) in &labels {
+        statements.insert_label.execute((id, lang, label))?;
+    }
+    for (lang, description) in &descriptions {
+        statements
+            .insert_description
+            .execute((id, lang, description))?;
+    }
+    for (lang, aliases) in item.aliases {
+        if config.keeps_label(lang) {
+            for alias in aliases {
+                statements.insert_alias.execute((id, lang, alias))?;
+            }
+        }
+    }
+    Ok((labels, descriptions))
+}
+
+/// Entry of a category in index.json: its labels, its descriptions to tell apart categories
+/// with the same name, and the globe of its places
+#[derive(Serialize)]
+struct IndexEntry {
+    #[serde(flatten)]
+    labels: IndexMap<String, String>,
+    #[serde(skip_serializing_if = "IndexMap::is_empty")]
+    descriptions: IndexMap<String, String>,
+    /// Celestial body of the places
+    globe: String,
+}
+
+/// Labels in the configured languages, or all of them, following the fallback chains; when a
+/// fallback is used, its language is recorded in `<lang>_fallback`. Empty when nothing matches.
+fn select_labels(config: &Config, labels: BTreeMap<String, String>) -> IndexMap<String, String> {
+    let resolve = |lang: &str| {
+        std::iter::once(lang)
+            .chain(config.label_fallbacks(lang).iter().map(String::as_str))
+            .find_map(|l| labels.get_key_value(l))
+    };
+    let languages: Vec<&str> = if config.languages.is_empty() {
+        // English is the default of the viewer
+        labels
+            .keys()
+            .map(String::as_str)
+            .chain(["en"])
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    } else {
+        config.languages.iter().map(String::as_str).collect()
+    };
+    let mut selected = IndexMap::new();
+    for lang in languages {
+        match resolve(lang) {
+            Some((from, label)) => {
+                selected.insert(lang.to_string(), label.clone());
+                if from != lang {
+                    selected.insert(format!("{lang}_fallback"), from.clone());
+                }
+            }
+            None if !config.languages.is_empty() => {
+                selected.insert(lang.to_string(), String::new());
+            }
+            None => {}
+        }
+    }
+    selected
+}
+
+/// Same as the labels, without the languages that have none: they are only shown when present
+fn select_descriptions(
+    config: &Config,
+    descriptions: BTreeMap<String, String>,
+) -> IndexMap<String, String> {
+    let mut selected = select_labels(config, descriptions);
+    selected.retain(|_, description| !description.is_empty());
+    selected
 }
 
 #[derive(Serialize)]
@@ -92,42 +303,97 @@ struct GeoJsonNode {
     geometry: GeoJsonNodeGeo,
 }
 impl GeoJsonNode {
-    fn new(en: String, fr: String, coordinates: [f64; 2]) -> Self {
-        Self {
+    fn try_from_row(value: &rusqlite::Row, config: &Config) -> Result<Self, Box<dyn Error>> {
+        let id: u64 = value.get(0)?;
+        let labels: String = value.get(1)?;
+        let labels = serde_json::from_str(&labels)?;
+        let sitelinks: String = value.get(2)?;
+        let precision = value.get(5)?;
+        let period = Period::try_from_row(value, 6)?;
+        let countries: String = value.get(10)?;
+        let located_in: String = value.get(11)?;
+        Ok(GeoJsonNode {
             typ: "Feature",
-            properties: GeoJsonNodeProp { en, fr },
+            properties: GeoJsonNodeProp {
+                qid: format!("Q{id}"),
+                labels: select_labels(config, labels),
+                sitelinks: serde_json::from_str(&sitelinks)?,
+                period,
+                attributes: Attributes {
+                    population: value.get(8)?,
+                    area: value.get(9)?,
+                    country: serde_json::from_str(&countries)?,
+                    located_in: serde_json::from_str(&located_in)?,
+                },
+            },
             geometry: GeoJsonNodeGeo {
                 typ: "Point",
-                coordinates,
+                coordinates: [
+                    round_coord(value.get(3)?, precision, config),
+                    round_coord(value.get(4)?, precision, config),
+                ],
             },
-        }
-    }
-}
-impl<'st> TryFrom<&rusqlite::Row<'st>> for GeoJsonNode {
-    type Error = Box<dyn Error>;
-
-    fn try_from(value: &rusqlite::Row<'st>) -> Result<Self, Self::Error> {
-        let name_en: String = value.get(0)?;
-        let name_fr: String = value.get(1)?;
-        let lon: String = value.get(2)?;
-        let lat: String = value.get(3)?;
-        Ok(GeoJsonNode::new(
-            name_en,
-            name_fr,
-            [
-                lon.parse()
-                    .map_err(|e| format!("failed to parse float {lon}: {e}"))?,
-                lat.parse()
-                    .map_err(|e| format!("failed to parse float {lat}: {e}"))?,
-            ],
-        ))
+        })
     }
 }
 
 #[derive(Serialize)]
 struct GeoJsonNodeProp {
-    en: String,
-    fr: String,
+    /// Not `id`, which is also the code of Indonesian labels
+    qid: String,
+    #[serde(flatten)]
+    labels: IndexMap<String, String>,
+    /// Page titles, by wiki
+    #[serde(flatten)]
+    sitelinks: BTreeMap<String, String>,
+    #[serde(flatten)]
+    period: Period,
+    #[serde(flatten)]
+    attributes: Attributes,
+}
+
+/// Only known when ingested with the attributes option
+#[derive(Serialize)]
+struct Attributes {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    population: Option<u64>,
+    /// In km²
+    #[serde(skip_serializing_if = "Option::is_none")]
+    area: Option<f64>,
+    /// Ids, valid at the generated date
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    country: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    located_in: Vec<String>,
+}
+
+/// Validity dates, for time filters in the viewer; absent when unbounded
+#[derive(Serialize, Clone, PartialEq)]
+struct Period {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end: Option<String>,
+}
+impl Period {
+    /// From the valid_from and valid_until timestamps, at index `i` of the row
+    fn try_from_row(row: &rusqlite::Row, i: usize) -> Result<Self, Box<dyn Error>> {
+        let date = |i| -> Result<Option<String>, Box<dyn Error>> {
+            match row.get::<_, Option<i64>>(i)? {
+                None => Ok(None),
+                Some(t) => Ok(Some(
+                    DateTime::from_timestamp(t, 0)
+                        .ok_or_else(|| format!("invalid timestamp {t}"))?
+                        .format("%Y-%m-%d")
+                        .to_string(),
+                )),
+            }
+        };
+        Ok(Period {
+            start: date(i)?,
+            end: date(i + 1)?,
+        })
+    }
 }
 #[derive(Serialize)]
 struct GeoJsonNodeGeo {
@@ -143,16 +409,17 @@ struct GeoJsonRootNodes<'a> {
     features: RowsNode<'a>,
 }
 impl<'a> GeoJsonRootNodes<'a> {
-    fn new(r: RefCell<rusqlite::Rows<'a>>) -> Self {
+    fn new(r: RefCell<rusqlite::Rows<'a>>, config: &'a Config) -> Self {
         Self {
             typ: "FeatureCollection",
-            features: RowsNode { r },
+            features: RowsNode { r, config },
         }
     }
 }
 
 struct RowsNode<'a> {
     r: RefCell<rusqlite::Rows<'a>>,
+    config: &'a Config,
 }
 
 impl<'a> Serialize for RowsNode<'a> {
@@ -170,7 +437,7 @@ impl<'a> Serialize for RowsNode<'a> {
             .next()
             .map_err(|e| ser::Error::custom(err_conv(e)))?
         {
-            let node = GeoJsonNode::try_from(ent).map_err(ser::Error::custom)?;
+            let node = GeoJsonNode::try_from_row(ent, self.config).map_err(ser::Error::custom)?;
             seq.serialize_element(&node)?;
         }
         seq.end()
@@ -178,45 +445,85 @@ impl<'a> Serialize for RowsNode<'a> {
 }
 
 #[derive(Serialize)]
-struct GeoJsonRootEdges<'a> {
+struct GeoJsonRootEdges<F> {
     #[serde(rename = "type")]
     typ: &'static str,
-    coordinates: RowsEdges<'a>,
+    features: F,
 }
-impl<'a> GeoJsonRootEdges<'a> {
-    fn new(rows: rusqlite::Rows<'a>) -> Self {
+impl<F: Serialize> GeoJsonRootEdges<F> {
+    fn new(features: F) -> Self {
         Self {
-            typ: "MultiLineString",
-            coordinates: RowsEdges {
-                r: RefCell::new(rows),
+            typ: "FeatureCollection",
+            features,
+        }
+    }
+}
+
+/// All the edges sharing the same validity period
+#[derive(Serialize)]
+struct GeoJsonEdges {
+    #[serde(rename = "type")]
+    typ: &'static str,
+    properties: Period,
+    geometry: GeoJsonEdgesGeo,
+}
+impl GeoJsonEdges {
+    fn new(period: Period) -> Self {
+        Self {
+            typ: "Feature",
+            properties: period,
+            geometry: GeoJsonEdgesGeo {
+                typ: "MultiLineString",
+                coordinates: vec![],
             },
         }
     }
 }
+#[derive(Serialize)]
+struct GeoJsonEdgesGeo {
+    #[serde(rename = "type")]
+    typ: &'static str,
+    coordinates: Vec<[[f64; 2]; 2]>,
+}
+
 struct RowsEdges<'a> {
     r: RefCell<rusqlite::Rows<'a>>,
+    config: &'a Config,
 }
 
 struct LineCoord {
     coordinates: [[f64; 2]; 2],
+    period: Period,
 }
-impl<'st> TryFrom<&rusqlite::Row<'st>> for LineCoord {
-    type Error = Box<dyn Error>;
-
-    fn try_from(value: &rusqlite::Row<'st>) -> Result<Self, Self::Error> {
-        let mut f: [f64; 4] = [999999.99; 4];
-        for (i, coord) in f.iter_mut().enumerate() {
-            let val: String = value.get(i)?;
-            *coord = val
-                .parse()
-                .map_err(|e| format!("failed to parse float {val}: {e}"))?;
-        }
+impl LineCoord {
+    fn try_from_row(value: &rusqlite::Row, config: &Config) -> Result<Self, Box<dyn Error>> {
+        let end = |lon, lat, precision| -> rusqlite::Result<[f64; 2]> {
+            let precision = value.get(precision)?;
+            Ok([
+                round_coord(value.get(lon)?, precision, config),
+                round_coord(value.get(lat)?, precision, config),
+            ])
+        };
         Ok(LineCoord {
-            coordinates: [[f[0], f[1]], [f[2], f[3]]],
+            coordinates: [end(0, 1, 4)?, end(2, 3, 5)?],
+            period: Period::try_from_row(value, 6)?,
         })
     }
 }
 
+/// Rounded to the decimals that are significant at this precision, at most the configured ones
+fn round_coord(degrees: f64, precision: Option<f64>, config: &Config) -> f64 {
+    let decimals = precision
+        .filter(|&p| p > 0.0)
+        .map_or(config.coordinate_decimals, |p| {
+            (-p.log10())
+                .ceil()
+                .clamp(0.0, config.coordinate_decimals as f64) as u32
+        });
+    let factor = 10f64.powi(decimals as i32);
+    (degrees * factor).round() / factor
+}
+
 // Failed to make it generic, let's copy paste instead
 impl<'a> Serialize for RowsEdges<'a> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -227,22 +534,167 @@ impl<'a> Serialize for RowsEdges<'a> {
             format!("got sql error {e}")
         }
         let mut seq = serializer.serialize_seq(None)?;
+        // Rows are sorted by period: group them in a feature per period, to keep files small
+        let mut feature: Option<GeoJsonEdges> = None;
         while let Some(ent) = self
             .r
             .borrow_mut()
             .next()
             .map_err(|e| ser::Error::custom(err_conv(e)))?
         {
-            let node = LineCoord::try_from(ent).map_err(ser::Error::custom)?;
-            seq.serialize_element(&node.coordinates)?;
+            let line = LineCoord::try_from_row(ent, self.config).map_err(ser::Error::custom)?;
+            if let Some(ref f) = feature
+                && f.properties != line.period
+            {
+                seq.serialize_element(f)?;
+                feature = None;
+            }
+            feature
+                .get_or_insert_with(|| GeoJsonEdges::new(line.period))
+                .geometry
+                .coordinates
+                .push(line.coordinates);
+        }
+        if let Some(f) = feature {
+            seq.serialize_element(&f)?;
         }
         seq.end()
     }
 }
+/// A single border, between places `a` and `b`
+#[derive(Serialize)]
+struct GeoJsonEdge {
+    #[serde(rename = "type")]
+    typ: &'static str,
+    properties: GeoJsonEdgeProp,
+    geometry: GeoJsonEdgeGeo,
+}
+#[derive(Serialize)]
+struct GeoJsonEdgeProp {
+    a: String,
+    b: String,
+    /// Labels of both places, prefixed with a_ and b_
+    #[serde(flatten)]
+    names: IndexMap<String, String>,
+    #[serde(flatten)]
+    period: Period,
+}
+#[derive(Serialize)]
+struct GeoJsonEdgeGeo {
+    #[serde(rename = "type")]
+    typ: &'static str,
+    coordinates: [[f64; 2]; 2],
+}
+impl GeoJsonEdge {
+    fn try_from_row(value: &rusqlite::Row, config: &Config) -> Result<Self, Box<dyn Error>> {
+        let line = LineCoord::try_from_row(value, config)?;
+        let mut names = IndexMap::new();
+        for (end, i) in [("a", 10), ("b", 11)] {
+            let labels: String = value.get(i)?;
+            let labels = select_labels(config, serde_json::from_str(&labels)?);
+            names.extend(
+                labels
+                    .into_iter()
+                    .map(|(lang, label)| (format!("{end}_{lang}"), label)),
+            );
+        }
+        Ok(GeoJsonEdge {
+            typ: "Feature",
+            properties: GeoJsonEdgeProp {
+                a: format!("Q{}", value.get::<_, u64>(8)?),
+                b: format!("Q{}", value.get::<_, u64>(9)?),
+                names,
+                period: line.period,
+            },
+            geometry: GeoJsonEdgeGeo {
+                typ: "LineString",
+                coordinates: line.coordinates,
+            },
+        })
+    }
+}
+
+struct RowsEdgeFeatures<'a> {
+    r: RefCell<rusqlite::Rows<'a>>,
+    config: &'a Config,
+}
+
+impl<'a> Serialize for RowsEdgeFeatures<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        fn err_conv(e: rusqlite::Error) -> String {
+            format!("got sql error {e}")
+        }
+        let mut seq = serializer.serialize_seq(None)?;
+        while let Some(ent) = self
+            .r
+            .borrow_mut()
+            .next()
+            .map_err(|e| ser::Error::custom(err_conv(e)))?
+        {
+            let edge = GeoJsonEdge::try_from_row(ent, self.config).map_err(ser::Error::custom)?;
+            seq.serialize_element(&edge)?;
+        }
+        seq.end()
+    }
+}
+
 type LabelsQuery<'a> = HashMap<&'a str, Cow<'a, str>>;
-fn label_q<'a>(labels: &LabelsQuery<'a>, lang: &str) -> Option<String> {
-    labels.get(lang).map(|l| l.to_string())
+#[derive(Deserialize)]
+struct ItemQuery<'a> {
+    #[serde(borrow, default)]
+    labels: LabelsQuery<'a>,
+    #[serde(borrow, default)]
+    descriptions: LabelsQuery<'a>,
+    #[serde(borrow, default)]
+    aliases: HashMap<&'a str, Vec<Cow<'a, str>>>,
 }
-fn label_or_empty_q<'a>(labels: &LabelsQuery<'a>, lang: &str) -> String {
-    label_q(labels, lang).unwrap_or_default()
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coordinate_rounding() {
+        let config = Config::default();
+        let round = |degrees, precision| round_coord(degrees, precision, &config);
+        // At most the configured decimals
+        assert_eq!(config.coordinate_decimals, 5);
+        assert_eq!(round(45.123456789, None), 45.12346);
+        assert_eq!(round(45.123456789, Some(1e-9)), 45.12346);
+        assert_eq!(round(45.123456789, Some(0.0)), 45.12346);
+        // The decimals significant at this precision
+        assert_eq!(round(45.123456789, Some(1.0)), 45.0);
+        assert_eq!(round(45.6, Some(1.0)), 46.0);
+        assert_eq!(round(45.123456789, Some(0.1)), 45.1);
+        assert_eq!(round(45.123456789, Some(0.05)), 45.12);
+        assert_eq!(round(45.123456789, Some(0.0001)), 45.1235);
+        // Rounded away from zero, not towards it
+        assert_eq!(round(-45.123456789, None), -45.12346);
+        assert_eq!(round(-45.6, Some(1.0)), -46.0);
+        assert_eq!(round(-0.25, Some(0.1)), -0.3);
+        assert_eq!(round(-73.98765, Some(0.01)), -73.99);
+    }
+
+    #[test]
+    fn missing_descriptions() {
+        let config = Config::default();
+        assert_eq!(config.languages, ["en", "fr"]);
+        let descriptions = BTreeMap::from([("en".to_string(), "commune of France".to_string())]);
+        assert_eq!(
+            select_descriptions(&config, descriptions.clone()),
+            IndexMap::from([("en".to_string(), "commune of France".to_string())])
+        );
+        // Unlike labels, that are all listed
+        assert_eq!(
+            select_labels(&config, descriptions),
+            IndexMap::from([
+                ("en".to_string(), "commune of France".to_string()),
+                ("fr".to_string(), String::new())
+            ])
+        );
+        assert!(select_descriptions(&config, BTreeMap::new()).is_empty());
+    }
 }
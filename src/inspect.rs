@@ -0,0 +1,196 @@
+// Human-readable views of the intermediate DB content
+use super::db;
+
+use std::error::Error;
+
+pub(crate) fn stats(conn: &rusqlite::Connection) -> Result<(), Box<dyn Error>> {
+    let count = |query: &str| conn.query_row(query, (), |row| row.get::<_, u64>(0));
+    println!("entities: {}", count("SELECT COUNT(*) FROM entities;")?);
+    println!(
+        "labels: {} ({} languages)",
+        count("SELECT COUNT(*) FROM labels;")?,
+        count("SELECT COUNT(DISTINCT lang) FROM labels;")?
+    );
+    println!(
+        "positions: {} ({} coordinates)",
+        count("SELECT COUNT(*) FROM positions;")?,
+        count("SELECT COUNT(*) FROM coordinates;")?
+    );
+    println!(
+        "natures: {} ({} distinct)",
+        count("SELECT COUNT(*) FROM natures;")?,
+        count("SELECT COUNT(DISTINCT nat) FROM natures;")?
+    );
+    println!("edges: {}", count("SELECT COUNT(*) FROM edges;")?);
+    println!(
+        "subclass relations: {}",
+        count("SELECT COUNT(*) FROM subclass;")?
+    );
+    match db::read_checkpoint(conn)? {
+        Some((c, dump)) if c.complete => {
+            println!("ingestion: complete, {} lines of {dump}", c.lines)
+        }
+        Some((c, dump)) => println!(
+            "ingestion: interrupted at line {} (offset {}) of {dump}",
+            c.lines, c.offset
+        ),
+        None => println!("ingestion: no checkpoint"),
+    }
+    if let Some(as_of) = db::get_metadata(conn, "as_of")? {
+        println!("claims valid as of: {as_of}");
+    }
+    deprecated(conn)?;
+    Ok(())
+}
+
+/// How many deprecated statements were ignored, per property
+pub(crate) fn deprecated(conn: &rusqlite::Connection) -> Result<(), Box<dyn Error>> {
+    let mut st = conn.prepare(
+        "SELECT property, count FROM deprecated_statements ORDER BY count DESC, property;",
+    )?;
+    let counts = st
+        .query_map((), |row| {
+            Ok(format!(
+                "{} {}",
+                row.get::<_, String>(0)?,
+                row.get::<_, u64>(1)?
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    if counts.is_empty() {
+        println!("ignored deprecated statements: none");
+    } else {
+        println!("ignored deprecated statements: {}", counts.join(", "));
+    }
+    Ok(())
+}
+
+pub(crate) fn query(conn: &rusqlite::Connection, id: u64) -> Result<(), Box<dyn Error>> {
+    let name = |id: u64| {
+        conn.query_row(
+            "SELECT label FROM labels WHERE id = ?1 AND lang IN ('en', 'mul') ORDER BY lang;",
+            (id,),
+            |row| row.get::<_, String>(0),
+        )
+    };
+    let label = |id: u64| match name(id) {
+        Ok(en) => format!("Q{id} ({en})"),
+        Err(_) => format!("Q{id}"),
+    };
+    let list = |query: &str| -> Result<Vec<String>, rusqlite::Error> {
+        let mut st = conn.prepare(query)?;
+        let ids = st
+            .query_map((id,), |row| row.get::<_, u64>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(ids.into_iter().map(label).collect())
+    };
+
+    let exists: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM entities WHERE id = ?1;",
+        (id,),
+        |row| row.get(0),
+    )?;
+    if !exists {
+        return Err(format!("Q{id} is not in the database").into());
+    }
+    println!("{}", label(id));
+    let texts = |query: &str| -> Result<String, rusqlite::Error> {
+        let mut st = conn.prepare(query)?;
+        let texts = st
+            .query_map((id,), |row| {
+                Ok(format!(
+                    "{}: {}",
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(texts.join(", "))
+    };
+    println!(
+        "labels: {}",
+        texts("SELECT lang, label FROM labels WHERE id = ?1 ORDER BY lang;")?
+    );
+    println!(
+        "descriptions: {}",
+        texts("SELECT lang, description FROM descriptions WHERE id = ?1 ORDER BY lang;")?
+    );
+    println!(
+        "aliases: {}",
+        texts("SELECT lang, alias FROM aliases WHERE id = ?1 ORDER BY lang, rowid;")?
+    );
+    match conn.query_row(
+        "SELECT lat, lon, precision, globe FROM positions WHERE id = ?1;",
+        (id,),
+        |row| {
+            Ok((
+                row.get::<_, f64>(0)?,
+                row.get::<_, f64>(1)?,
+                row.get::<_, Option<f64>>(2)?,
+                row.get::<_, u64>(3)?,
+            ))
+        },
+    ) {
+        Ok((lat, lon, precision, globe)) => {
+            let precision = precision.map_or("unknown".to_string(), |p| p.to_string());
+            println!("position: {lat}, {lon} (precision {precision}, globe Q{globe})")
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => println!("position: none"),
+        Err(e) => return Err(e.into()),
+    }
+    let mut st = conn.prepare(
+        "SELECT lat, lon, rank, part, candidate FROM coordinates WHERE id = ?1 ORDER BY rowid;",
+    )?;
+    let coordinates = st
+        .query_map((id,), |row| {
+            let part = row
+                .get::<_, Option<u64>>(3)?
+                .map_or(String::new(), |part| format!(" of Q{part}"));
+            let candidate = if row.get(4)? { ", candidate" } else { "" };
+            Ok(format!(
+                "{}, {} ({}{part}{candidate})",
+                row.get::<_, f64>(0)?,
+                row.get::<_, f64>(1)?,
+                row.get::<_, String>(2)?
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    println!("coordinates: {}", coordinates.join("; "));
+    println!(
+        "natures: {}",
+        list("SELECT nat FROM natures WHERE id = ?1 ORDER BY nat;")?.join(", ")
+    );
+    println!(
+        "borders: {}",
+        list(
+            "SELECT b FROM edges WHERE a = ?1 UNION SELECT a FROM edges WHERE b = ?1 ORDER BY 1;"
+        )?
+        .join(", ")
+    );
+    let mut st = conn.prepare(
+        "SELECT CASE WHEN a = ?1 THEN b ELSE a END, refs, length, subject_of FROM edge_qualifiers
+            WHERE source = ?1 ORDER BY rowid;",
+    )?;
+    let statements = st
+        .query_map((id,), |row| {
+            let mut statement = format!(
+                "Q{} ({} references",
+                row.get::<_, u64>(0)?,
+                row.get::<_, u64>(1)?
+            );
+            if let Some(length) = row.get::<_, Option<f64>>(2)? {
+                statement += &format!(", {length} km");
+            }
+            if let Some(subject) = row.get::<_, Option<u64>>(3)? {
+                statement += &format!(", subject of Q{subject}");
+            }
+            Ok(statement + ")")
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    println!("border statements: {}", statements.join(", "));
+    println!(
+        "subclass of: {}",
+        list("SELECT parent FROM subclass WHERE id = ?1 ORDER BY parent;")?.join(", ")
+    );
+    Ok(())
+}
@@ -1,162 +1,471 @@
+mod bz2;
+mod cli;
+mod config;
 mod db;
+mod dump;
 mod geojson;
+mod inspect;
+mod parallel;
+mod report;
+mod update;
 
 use std::borrow::Cow;
-use std::collections::{HashMap, HashSet};
-use std::env;
+use std::collections::HashMap;
 use std::error::Error;
-use std::io::{BufRead, BufReader};
-use std::process;
+use std::io::{BufRead, BufReader, Read};
+use std::thread;
 
-use chrono::DateTime;
-use db::int_id;
+use bz2::BlockStarts;
+use chrono::{DateTime, Utc};
+use clap::CommandFactory;
+use clap::Parser;
+use cli::{Cli, Command};
+use config::Config;
 use db::int_id_faillible;
 use memchr::memmem;
+use parallel::Feeder;
+use rusqlite::OpenFlags;
 use serde::Deserialize;
+use serde::de::IgnoredAny;
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let mut config = Config::default();
-    let mut args = env::args().skip(1);
-    if let Some(file) = args.next() {
-        config.intermediate_db_filename = file;
+    let cli = Cli::parse();
+    let mut config = Config::load(cli.config.as_deref())?;
+    if let Some(db) = cli.db {
+        config.intermediate_db_filename = db;
     }
-    if let Some(file) = args.next() {
-        config.wikidata_dump_filename = Some(file);
+    match cli.command {
+        Some(Command::Ingest {
+            ref natures, as_of, ..
+        }) => {
+            if !natures.is_empty() {
+                config.filtered_natures = natures.clone();
+            }
+            config.as_of = as_of.or(config.as_of);
+        }
+        Some(Command::Update { as_of, .. }) => config.as_of = as_of.or(config.as_of),
+        Some(Command::Generate {
+            date, all_periods, ..
+        })
+        | Some(Command::Export {
+            date, all_periods, ..
+        }) => {
+            config.as_of = date.or(config.as_of);
+            config.all_periods = all_periods;
+        }
+        Some(Command::Report { date, .. }) => config.as_of = date.or(config.as_of),
+        _ => {}
     }
-    if let Some(natures) = args.next() {
-        config.filtered_natures = natures
-            .split(",")
-            .skip_while(|s| s.is_empty())
-            .map(String::from)
-            .collect();
+    if cli.print_config {
+        print!("{}", toml::to_string_pretty(&config)?);
+        return Ok(());
     }
-    let mut conn = rusqlite::Connection::open(&config.intermediate_db_filename)?;
-    /* YOLO, we need speed, and to save disk space */
+    let Some(command) = cli.command else {
+        Cli::command().print_help()?;
+        return Err("missing command".into());
+    };
+    let open_flags = if matches!(command, Command::Ingest { .. }) {
+        OpenFlags::default()
+    } else {
+        // Only ingestion creates the DB
+        OpenFlags::default() - OpenFlags::SQLITE_OPEN_CREATE
+    };
+    let mut conn =
+        rusqlite::Connection::open_with_flags(&config.intermediate_db_filename, open_flags)
+            .map_err(|e| format!("Cannot open {}: {e}", config.intermediate_db_filename))?;
+    /* YOLO, we need speed. The WAL keeps the DB consistent if we get killed, so that an
+     * interrupted ingestion can be resumed from its last checkpoint */
     conn.execute("PRAGMA synchronous = off;", ())?;
-    let _: () = conn.query_row_and_then("PRAGMA journal_mode = memory;", [], |row| {
+    let _: () = conn.query_row_and_then("PRAGMA journal_mode = wal;", [], |row| {
         let journal_mode: String = row.get(0)?;
-        assert_eq!(journal_mode, "memory");
+        assert_eq!(journal_mode, "wal");
         Ok::<(), Box<dyn Error>>(())
     })?;
 
-    /* If no dump filename is passed, we consider that we already have an sqlite file to work with */
-    if config.wikidata_dump_filename.is_some() {
-        db::create_tables(&mut conn, &config.banned_generic_categories)?;
+    match command {
+        Command::Ingest { dump, resume, .. } => {
+            config.wikidata_dump_filename = Some(dump);
+            ingest(&mut config, &mut conn, resume)
+        }
+        Command::Update { source, .. } => {
+            // Updates are fresh
+            config.as_of = config.as_of.or_else(|| Some(config::today()));
+            match db::read_checkpoint(&conn)? {
+                Some((c, _)) if c.complete => {}
+                Some(_) => {
+                    return Err(
+                        "Previous ingestion is incomplete, use ingest --resume first".into(),
+                    );
+                }
+                None => return Err("No previous ingestion to update".into()),
+            }
+            ingested_natures(&mut config, &conn)?;
+            let mut statements = db::Statements::new(&conn);
+            update::update_db(&config, &conn, &mut statements, &source)
+        }
+        Command::Generate { output, .. } => {
+            snapshot_date(&mut config, &conn)?;
+            print_generated_date(&config);
+            let mut statements = db::Statements::new(&conn);
+            geojson::generate(&mut statements, &config, &output)
+        }
+        Command::Stats => inspect::stats(&conn),
+        Command::Query { id } => inspect::query(&conn, int_id_faillible(&id)?),
+        Command::Export {
+            category, output, ..
+        } => {
+            snapshot_date(&mut config, &conn)?;
+            print_generated_date(&config);
+            let mut statements = db::Statements::new(&conn);
+            geojson::export(
+                &mut statements,
+                &config,
+                int_id_faillible(&category)?,
+                &output,
+            )
+        }
+        Command::Report {
+            report,
+            category,
+            format,
+            output,
+            ..
+        } => {
+            snapshot_date(&mut config, &conn)?;
+            let mut statements = db::Statements::new(&conn);
+            let category = category.as_deref().map(int_id_faillible).transpose()?;
+            report::report(
+                &mut statements,
+                &config,
+                report,
+                category,
+                format,
+                output.as_deref(),
+            )
+        }
+    }
+}
+
+/// Generate files as of the ingested dump, unless another date was asked for
+fn snapshot_date(config: &mut Config, conn: &rusqlite::Connection) -> Result<(), Box<dyn Error>> {
+    if config.as_of.is_none() {
+        config.as_of = db::get_metadata(conn, "as_of")?
+            .map(|d| d.parse())
+            .transpose()
+            .map_err(|e| format!("invalid as_of date in metadata table: {e}"))?;
     }
-    let mut statements = db::Statements::new(&conn);
-    if config.wikidata_dump_filename.is_some() {
-        fill_db_from_dump(&config, &mut statements)?;
+    Ok(())
+}
+
+/// Entities of other natures than those of the ingestion must not be added when resuming or
+/// updating it
+fn ingested_natures(
+    config: &mut Config,
+    conn: &rusqlite::Connection,
+) -> Result<(), Box<dyn Error>> {
+    // Ingested by an older version
+    let Some(natures) = db::get_metadata(conn, "natures")? else {
+        return Ok(());
+    };
+    let natures: Vec<String> = natures
+        .split(',')
+        .filter(|n| !n.is_empty())
+        .map(String::from)
+        .collect();
+    if !config.filtered_natures.is_empty() && config.filtered_natures != natures {
+        return Err(format!(
+            "The database was ingested with natures [{}], not [{}]",
+            natures.join(","),
+            config.filtered_natures.join(",")
+        )
+        .into());
     }
-    geojson::generate(&mut statements, &config.banned_generic_categories)?;
+    config.filtered_natures = natures;
     Ok(())
 }
-struct Config {
-    // I initially envisionned a pipeline that would be heavily configurable. But this is at odds
-    // with putting things in a fixed-schema SQL DB, otherwise we'd just be replicating the
-    // original wikidata graph database
-    //
-    // The below config options are provided with hardcoded defaults. There is no plan to make them
-    // any more configurable without modifying the code at this time
-    wikidata_dump_filename: Option<String>,
-    mandatory_claims: Vec<&'static str>,
-    filtered_natures: Vec<String>,
-    intermediate_db_filename: String,
-
-    banned_generic_categories: HashSet<u64>,
-    banned_parents: HashSet<u64>,
+
+fn print_generated_date(config: &Config) {
+    if config.all_periods {
+        println!(
+            "Generating borders of every period, categories as of {}",
+            config.as_of().date_naive()
+        );
+    } else {
+        println!("Generating borders as of {}", config.as_of().date_naive());
+    }
+}
+
+fn ingest(
+    config: &mut Config,
+    conn: &mut rusqlite::Connection,
+    resume: bool,
+) -> Result<(), Box<dyn Error>> {
+    let dump = config
+        .wikidata_dump_filename
+        .clone()
+        .ok_or("missing dump file name")?;
+    let checkpoint = if resume {
+        db::read_checkpoint(conn)?
+    } else {
+        None
+    };
+    match checkpoint {
+        None => {
+            // Along with a first checkpoint, so that an ingestion interrupted right away can be
+            // resumed too
+            db::begin(conn)?;
+            db::create_tables(conn, &config.banned_generic_categories).map_err(|e| {
+                format!(
+                    "Cannot create tables: {e}; to continue an interrupted ingestion, use --resume"
+                )
+            })?;
+            db::set_metadata(conn, "natures", config.filtered_natures.join(","))?;
+            db::checkpoint(conn, &dump, &db::Checkpoint::default())?;
+        }
+        // Its offset would be meaningless in another dump
+        Some((_, ref previous_dump)) if !dump::same(previous_dump, &dump) => {
+            return Err(format!(
+                "Cannot resume the ingestion of {previous_dump} with another dump, {dump}"
+            )
+            .into());
+        }
+        Some(_) => {}
+    }
+    if checkpoint.is_some() {
+        ingested_natures(config, conn)?;
+    }
+    let start = checkpoint.as_ref().map(|(c, _)| *c).unwrap_or_default();
+    if start.complete {
+        println!("Ingestion already complete, nothing to resume");
+        return Ok(());
+    }
+    // Keep the date of the interrupted ingestion when resuming, for consistency
+    let previous_as_of = match checkpoint {
+        Some(_) => db::get_metadata(conn, "as_of")?.and_then(|d| d.parse().ok()),
+        None => None,
+    };
+    let as_of = config
+        .as_of
+        .or(previous_as_of)
+        .or_else(|| dump::date(&dump))
+        .unwrap_or_else(config::today);
+    config.as_of = Some(as_of);
+    println!("Subclasses kept, and borders generated by default, as of {as_of}");
+    db::set_metadata(conn, "as_of", as_of.to_string())?;
+    let config = &*config;
+    let mut statements = db::Statements::new(conn);
+    fill_db_from_dump(config, conn, &mut statements, start)
 }
 pub(crate) const NATURE_CLAIM: &str = "P31";
 pub(crate) const POSITION_CLAIM: &str = "P625";
 pub(crate) const SHARES_BORDER_WITH_CLAIM: &str = "P47";
 const EXPIRY_CLAIM: &str = "P582";
+const START_CLAIM: &str = "P580";
+const INCEPTION_CLAIM: &str = "P571";
+const DISSOLVED_CLAIM: &str = "P576";
 const SUBJECT_ROLE_CLAIM: &str = "P2868";
 pub(crate) const SUBCLASS_OF_CLAIM: &str = "P279";
+const POINT_IN_TIME_CLAIM: &str = "P585";
+pub(crate) const POPULATION_CLAIM: &str = "P1082";
+pub(crate) const AREA_CLAIM: &str = "P2046";
+pub(crate) const COUNTRY_CLAIM: &str = "P17";
+pub(crate) const LOCATED_IN_CLAIM: &str = "P131";
+pub(crate) const APPLIES_TO_PART_CLAIM: &str = "P518";
+pub(crate) const LENGTH_CLAIM: &str = "P2043";
+pub(crate) const SUBJECT_OF_CLAIM: &str = "P805";
+/// Globe of coordinates that don't have one
+pub(crate) const EARTH: &str = "Q2";
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            wikidata_dump_filename: None,
-            mandatory_claims: vec![NATURE_CLAIM, POSITION_CLAIM, SHARES_BORDER_WITH_CLAIM],
-            filtered_natures: vec![],
-            intermediate_db_filename: "border-explorer.db".to_string(),
-
-            // Block those generic (too broad) categories that can be in many separate places, or
-            // not useful enough to display in general
-            banned_generic_categories: parse_banned_categories(include_str!(
-                "../banned-categories.tsv"
-            )),
-            /* Hardcoded filter list for top parents reponsible for 30% of the table */
-            banned_parents: HashSet::from([11173, 20747295, 8054, 7187, 277338]),
-        }
-    }
-}
-fn parse_banned_categories(s: &'static str) -> HashSet<u64> {
-    s.lines()
-        // Ignore comments
-        .filter(|l| l.bytes().next().unwrap_or(b'#') != b'#')
-        // Keep only first column of Tab-Separated-Values
-        .filter_map(|l| l.split("\t").next())
-        .map(int_id)
-        .collect()
+/// Lines of the dump, processed together on a parsing thread
+struct Batch {
+    first_line: usize,
+    /// Progress once this batch is inserted
+    end: db::Checkpoint,
+    lines: Vec<String>,
 }
+const BATCH_SIZE: usize = 256;
+/// Commit and record progress about every minute of processing
+const CHECKPOINT_LINES: usize = 1_000_000;
+type BatchRows = Vec<Result<db::Rows, String>>;
+
 fn fill_db_from_dump(
     config: &Config,
+    conn: &rusqlite::Connection,
     statements: &mut db::Statements,
+    start: db::Checkpoint,
 ) -> Result<(), Box<dyn Error>> {
-    let mut cat = lbzcat(
-        config
-            .wikidata_dump_filename
-            .as_ref()
-            .ok_or("missing dump file name")?,
-    )?;
-    if let Some(ref mut stdout) = cat.stdout {
-        BufReader::with_capacity(64 * 1024, stdout)
-            .lines()
-            .map_while(Result::ok)
-            .enumerate()
-            // Skip empty first and last line
-            .filter(|(_, line)| line.len() > 2)
-            // cheap filter for faster processing; grepping multiple claims is much faster than
-            // json parsing, and does faster elimination of non-matching content
-            .filter(|(_, line)| {
-                config
-                    .mandatory_claims
-                    .iter()
-                    .all(|claim| grep(line, claim))
-                    || grep(line, SUBCLASS_OF_CLAIM)
-            })
-            .for_each(|(i, l)| {
-                let el = parse(&l);
-                let res: Result<(), Box<dyn Error>> = (|| {
-                    if query(&el, config) {
-                        //println!("{_i}: {}", _format(&el));
-                        db::insert_base(statements, &el)?;
-                        db::insert(statements, &el);
-                    } else if el.claims.contains_key(SUBCLASS_OF_CLAIM) {
-                        db::insert_base(statements, &el)?;
-                        db::insert_subclass(statements, &el, &config.banned_parents);
-                    }
-                    Ok(())
-                })();
-                if let Err(e) = res {
-                    println!("Error at line {i} (id {}): {e}\n{l}", el.id);
-                }
-            });
+    let filename = config
+        .wikidata_dump_filename
+        .as_ref()
+        .ok_or("missing dump file name")?;
+    if start.lines > 0 {
+        println!(
+            "Resuming at line {} (decompressed offset {})",
+            start.lines, start.offset
+        );
     }
+    let (input, starts) = dump::open_at(filename, start.offset, start.block)?;
+    let threads = thread::available_parallelism().map_or(4, |n| n.get());
+    thread::scope(|s| {
+        // Batches read but not inserted yet, including those parsed ahead of a slow one
+        let (batches, workers, mut parsed) = parallel::ordered(threads * 4);
+        let reader = s.spawn(move || read_batches(input, starts, start, batches));
+        workers.start(threads, |worker| {
+            s.spawn(move || {
+                worker.run(|batch: Batch| {
+                    let rows: BatchRows = batch
+                        .lines
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(n, l)| process_line(config, batch.first_line + n, l))
+                        .collect();
+                    (batch.end, rows)
+                })
+            });
+        });
 
-    let res = cat.wait().map_err(|e| format!("Could not wait: {e}"))?;
-    res.success().then_some(()).ok_or("failure")?;
-    Ok(())
+        // Single writer, inserting in dump order: the DB content and generated files must not
+        // depend on thread scheduling
+        let mut progress = start;
+        let mut last_checkpoint = start;
+        db::begin(conn)?;
+        let parsing = loop {
+            let (end, rows) = match parsed.next() {
+                Ok(Some(batch)) => batch,
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(format!("Failed to parse the dump: {e}")),
+            };
+            for res in rows {
+                match res {
+                    Ok(rows) => db::insert(statements, &rows),
+                    Err(e) => println!("{e}"),
+                }
+            }
+            progress = end;
+            if progress.lines - last_checkpoint.lines >= CHECKPOINT_LINES {
+                db::checkpoint(conn, filename, &progress)?;
+                db::begin(conn)?;
+                last_checkpoint = progress;
+            }
+        };
+        // Stops the reader, if parsing failed
+        drop(parsed);
+        let res = reader.join().expect("reader thread panicked").and(parsing);
+        // Even on error, keep what we could process; complete only once every batch read was
+        // inserted
+        progress.complete = res.is_ok();
+        // In the same transaction as the last checkpoint, not to be skipped when resuming
+        if progress.complete {
+            db::pick_positions(conn, config.as_of())?;
+        }
+        db::checkpoint(conn, filename, &progress)?;
+        res?;
+        inspect::deprecated(conn)
+    })
 }
 
-fn lbzcat(file: &str) -> Result<process::Child, String> {
-    let cat = process::Command::new("lbzcat")
-        .arg(file)
-        .stdout(process::Stdio::piped())
-        .spawn()
-        .map_err(|e| format!("failed to launch lbzcat: {e}"))?;
+fn read_batches(
+    input: Box<dyn Read + Send>,
+    starts: Option<BlockStarts>,
+    start: db::Checkpoint,
+    batches: Feeder<Batch>,
+) -> Result<(), String> {
+    let mut input = BufReader::with_capacity(64 * 1024, input);
+    let mut first_line = start.lines;
+    let mut progress = start;
+    let mut send = |lines: Vec<String>, mut end: db::Checkpoint| {
+        // Only the bz2 decoder can restart from a block; otherwise keep none, so that a resume
+        // decompresses from the start
+        end.block = starts.as_ref().and_then(|starts| starts.before(end.offset));
+        let batch = Batch {
+            first_line,
+            end,
+            lines,
+        };
+        first_line = end.lines;
+        // The writer is gone only on error, which will be propagated
+        batches.send(batch)
+    };
+    let mut lines = Vec::with_capacity(BATCH_SIZE);
+    loop {
+        let mut line = String::new();
+        match input.read_line(&mut line) {
+            Ok(0) => break,
+            Ok(n) => {
+                progress.lines += 1;
+                progress.offset += n as u64;
+                if line.ends_with('\n') {
+                    line.pop();
+                }
+                lines.push(line);
+            }
+            Err(e) => {
+                // Still process what we could read before the error
+                send(lines, progress);
+                return Err(format!(
+                    "Failed to read dump at line {}: {e}",
+                    progress.lines
+                ));
+            }
+        }
+        if lines.len() == BATCH_SIZE
+            && !send(
+                std::mem::replace(&mut lines, Vec::with_capacity(BATCH_SIZE)),
+                progress,
+            )
+        {
+            return Ok(());
+        }
+    }
+    send(lines, progress);
+    Ok(())
+}
 
-    Ok(cat)
+fn process_line(config: &Config, i: usize, l: &str) -> Option<Result<db::Rows, String>> {
+    // Skip empty first and last line
+    if l.len() <= 2 {
+        return None;
+    }
+    // cheap filter for faster processing; grepping multiple claims is much faster than
+    // json parsing, and does faster elimination of non-matching content
+    if !(config.mandatory_claims.iter().all(|claim| grep(l, claim))
+        || grep(l, SUBCLASS_OF_CLAIM)
+        || (config.borderless_places && grep(l, NATURE_CLAIM) && grep(l, POSITION_CLAIM)))
+    {
+        return None;
+    }
+    let mut el = match parse(l) {
+        Ok(el) => el,
+        Err(e) => return Some(Err(format!("Error at line {i}: {e}\n{l}"))),
+    };
+    el.drop_deprecated();
+    let mut rows = db::Rows::default();
+    let res: Result<(), Box<dyn Error>> = (|| {
+        if query(&el, config) {
+            //println!("{i}: {}", _format(&el, config.as_of()));
+            db::extract_base(&mut rows, &el, config)?;
+            db::extract(&mut rows, &el, config);
+            if config.attributes {
+                db::extract_attributes(&mut rows, &el);
+            }
+        } else if el.claims.contains_key(SUBCLASS_OF_CLAIM) {
+            db::extract_base(&mut rows, &el, config)?;
+            db::extract_subclass(&mut rows, &el, &config.banned_parents, config.as_of());
+        } else if config.borderless_places
+            && el.claims.contains_key(NATURE_CLAIM)
+            && el.claims.contains_key(POSITION_CLAIM)
+            && filtered_nature(&el, config)
+        {
+            // Not drawn without a border of its own, but neighbours stating one with it are
+            // then listed in the asymmetric report
+            db::extract_borderless(&mut rows, &el, config);
+        }
+        Ok(())
+    })();
+    Some(
+        res.map(|()| rows)
+            .map_err(|e| format!("Error at line {i} (id {}): {e}\n{l}", el.id)),
+    )
 }
 
 fn grep(line: &str, needle: &str) -> bool {
@@ -171,12 +480,50 @@ struct Element<'a> {
     claims: HashMap<&'a str, Vec<Claim<'a>>>,
     id: &'a str,
     labels: Labels<'a>,
+    #[serde(default)]
+    descriptions: Labels<'a>,
+    #[serde(default)]
+    aliases: HashMap<&'a str, Vec<Label<'a>>>,
+    #[serde(default)]
+    sitelinks: HashMap<&'a str, Sitelink<'a>>,
+    /// Properties of the deprecated statements that were dropped
+    #[serde(skip)]
+    deprecated: Vec<&'a str>,
+}
+impl<'a> Element<'a> {
+    /// Deprecated statements are known to be wrong, ignore them everywhere
+    fn drop_deprecated(&mut self) {
+        for (property, claims) in self.claims.iter_mut() {
+            claims.retain(|claim| {
+                let deprecated = claim.rank == Rank::Deprecated;
+                if deprecated {
+                    self.deprecated.push(property);
+                }
+                !deprecated
+            });
+        }
+        self.claims.retain(|_, claims| !claims.is_empty());
+        self.deprecated.sort();
+    }
 }
 #[derive(Deserialize, Debug)]
 struct Claim<'a> {
     #[serde(borrow)]
     mainsnak: Snak<'a>,
     qualifiers: Option<HashMap<&'a str, Vec<Snak<'a>>>>,
+    #[serde(default)]
+    rank: Rank,
+    /// Only counted
+    #[serde(default)]
+    references: Vec<IgnoredAny>,
+}
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Rank {
+    Preferred,
+    #[default]
+    Normal,
+    Deprecated,
 }
 
 #[derive(Debug, Deserialize)]
@@ -189,14 +536,40 @@ enum Snak<'a> {
         value: Value<'a>,
     },
     #[serde(rename = "globe-coordinate")]
-    GlobeCoordinate { value: Coord },
+    GlobeCoordinate {
+        #[serde(borrow)]
+        value: Coord<'a>,
+    },
     #[serde(rename = "time")]
     Time { value: Time<'a> },
-
-    // The rest
+    #[serde(rename = "quantity")]
+    Quantity {
+        #[serde(borrow)]
+        value: Quantity<'a>,
+    },
+    // Not extracted yet
     #[allow(unused)]
+    #[serde(rename = "string")]
+    String {
+        #[serde(borrow)]
+        value: Cow<'a, str>,
+    },
+    #[allow(unused)]
+    #[serde(rename = "external-id")]
+    ExternalId {
+        #[serde(borrow)]
+        value: Cow<'a, str>,
+    },
+    #[allow(unused)]
+    #[serde(rename = "monolingualtext")]
+    MonolingualText {
+        #[serde(borrow)]
+        value: MonolingualText<'a>,
+    },
+
+    // The rest, including unknown values (somevalue) and no values (novalue)
     #[serde(untagged)]
-    Unknown(serde_json::Value),
+    Unknown(IgnoredAny),
 }
 #[derive(Debug, Deserialize)]
 struct Time<'a> {
@@ -204,13 +577,40 @@ struct Time<'a> {
     precision: u8,
 }
 #[derive(Debug, Deserialize)]
+struct Quantity<'a> {
+    amount: &'a str,
+    /// Entity URI of the unit, or "1" without unit
+    #[serde(borrow)]
+    unit: Cow<'a, str>,
+    #[allow(unused)]
+    #[serde(rename = "upperBound")]
+    upper_bound: Option<&'a str>,
+    #[allow(unused)]
+    #[serde(rename = "lowerBound")]
+    lower_bound: Option<&'a str>,
+}
+#[derive(Debug, Deserialize)]
+struct MonolingualText<'a> {
+    #[allow(unused)]
+    #[serde(borrow)]
+    text: Cow<'a, str>,
+    #[allow(unused)]
+    language: &'a str,
+}
+#[derive(Debug, Deserialize)]
 struct Value<'a> {
     id: &'a str,
 }
 #[derive(Debug, Deserialize)]
-struct Coord {
+struct Coord<'a> {
     latitude: f64,
     longitude: f64,
+    /// In degrees, when known
+    #[serde(default)]
+    precision: Option<f64>,
+    /// Entity URI of the celestial body
+    #[serde(borrow, default)]
+    globe: Option<Cow<'a, str>>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -218,10 +618,15 @@ struct Label<'a> {
     //language: &'a str,
     value: Cow<'a, str>,
 }
-fn parse<'a>(l: &'a str) -> Element<'a> {
+#[derive(Debug, Deserialize)]
+struct Sitelink<'a> {
+    #[serde(borrow)]
+    title: Cow<'a, str>,
+}
+fn parse<'a>(l: &'a str) -> Result<Element<'a>, String> {
     //println!("line: {l}");
-    let el: Element = serde_json::from_str(&l[0..(l.len() - 1)]).expect("not json");
-    el
+    // Dump lines end with a comma, except the last one
+    serde_json::from_str(l.strip_suffix(',').unwrap_or(l)).map_err(|e| format!("not json: {e}"))
 }
 fn query<'a>(el: &Element<'a>, config: &Config) -> bool {
     //println!("{l}");
@@ -229,86 +634,158 @@ fn query<'a>(el: &Element<'a>, config: &Config) -> bool {
     if !config
         .mandatory_claims
         .iter()
-        .all(|claim| el.claims.contains_key(claim))
+        .all(|claim| el.claims.contains_key(claim.as_str()))
     {
         return false;
     }
+    filtered_nature(el, config)
+}
 
+/// Whether the entity has (or had) one of the filtered natures, if any
+fn filtered_nature<'a>(el: &Element<'a>, config: &Config) -> bool {
     if config.filtered_natures.is_empty() {
         return true;
     }
 
-    if let Some(nature) = el.claims.get(NATURE_CLAIM) {
-        if nature.iter().any(|nat| {
+    if let Some(nature) = el.claims.get(NATURE_CLAIM)
+        && nature.iter().any(|nat| {
             //print!(".");
             config.filtered_natures.iter().any(|possible_nature| {
+                // Whenever it had this nature: the history is kept
                 if let Snak::Item { value } = &nat.mainsnak {
-                    value.id == possible_nature && claim_still_valid(nat)
+                    value.id == possible_nature
                 } else {
                     false
                 }
             })
-        }) {
-            return true;
-        }
+        })
+    {
+        return true;
     }
     false
 }
 
-pub(crate) fn claim_still_valid(claim: &Claim) -> bool {
-    // check qualifier P582 (expiry date) of this claim
-    if let Some(ref qualifiers) = claim.qualifiers {
-        if let Some(expiries) = qualifiers.get(EXPIRY_CLAIM) {
-            // Is it expired ? fixed date
-            if claim_before(
-                expiries,
-                // TODO: use current year instead
-                DateTime::parse_from_rfc3339("2025-01-01T00:00:00+00:00").expect("Cannot fail"),
-            ) {
-                return false;
-            }
-        }
+/// When a claim holds, from its start time (P580) and end time (P582) qualifiers; a missing bound
+/// means it always held on that side
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub(crate) struct Interval {
+    pub(crate) start: Option<DateTime<Utc>>,
+    pub(crate) end: Option<DateTime<Utc>>,
+}
+impl Interval {
+    pub(crate) fn contains(&self, date: DateTime<Utc>) -> bool {
+        self.start.is_none_or(|start| start <= date) && self.end.is_none_or(|end| date <= end)
     }
-    true
 }
 
-fn claim_before<Tz: chrono::TimeZone>(p582_qualifiers: &[Snak], cutoff: DateTime<Tz>) -> bool {
-    p582_qualifiers.iter().all(|expiry| {
-        if let Snak::Time { value } = expiry {
-            //println!("'{}' (precision {})", value.time, value.precision);
+pub(crate) fn claim_interval(claim: &Claim) -> Interval {
+    let Some(ref qualifiers) = claim.qualifiers else {
+        return Interval::default();
+    };
+    Interval {
+        start: qualifiers.get(START_CLAIM).and_then(claim_start),
+        end: qualifiers.get(EXPIRY_CLAIM).and_then(claim_end),
+    }
+}
 
-            let s = match value.precision {
-                0..=9 => &format!("{}-01-01T00:00:00Z", &value.time[..5]),
-                10 => &format!("{}-01T00:00:00Z", &value.time[..8]),
-                _ => value.time,
-            };
-            let dt = match DateTime::parse_from_str(s, "%+") {
-                Ok(dt) => dt.to_utc(),
-                Err(e) => {
-                    println!(
-                        "Cannot parse date '{}' of precision {}: {e}",
-                        s, value.precision
-                    );
-                    /* Unparseable date, assume it's probably too old (year-only), and therefore
-                     * the before the date we target*/
-                    return true;
-                }
-            };
-            dt < cutoff
+pub(crate) fn claim_still_valid(claim: &Claim, as_of: DateTime<Utc>) -> bool {
+    claim_interval(claim).contains(as_of)
+}
+
+/// When the entity existed, from its inception (P571) and dissolution (P576) claims
+pub(crate) fn entity_interval(item: &Element) -> Interval {
+    let times = |claim| {
+        item.claims
+            .get(claim)
+            .map(|claims| claims.iter().map(|c| &c.mainsnak))
+    };
+    Interval {
+        start: times(INCEPTION_CLAIM).and_then(claim_start),
+        end: times(DISSOLVED_CLAIM).and_then(claim_end),
+    }
+}
+
+/// Earliest start time; unbounded if one of them is unknown (somevalue) or not a time
+fn claim_start<'s, 'a: 's>(
+    starts: impl IntoIterator<Item = &'s Snak<'a>>,
+) -> Option<DateTime<Utc>> {
+    starts
+        .into_iter()
+        .map(|start| {
+            if let Snak::Time { value } = start {
+                Some(parse_time(value).unwrap_or_else(|e| {
+                    println!("{e}");
+                    /* Unparseable date, assume it's probably old (year-only), so started before
+                     * any date we target */
+                    DateTime::<Utc>::MIN_UTC
+                }))
+            } else {
+                None
+            }
+        })
+        .try_fold(DateTime::<Utc>::MAX_UTC, |earliest, start| {
+            Some(earliest.min(start?))
+        })
+}
+
+/// Latest end time; unbounded if one of them is unknown (somevalue) or not a time
+fn claim_end<'s, 'a: 's>(ends: impl IntoIterator<Item = &'s Snak<'a>>) -> Option<DateTime<Utc>> {
+    ends.into_iter()
+        .map(|expiry| {
+            if let Snak::Time { value } = expiry {
+                Some(parse_time(value).unwrap_or_else(|e| {
+                    println!("{e}");
+                    /* Same as above: probably old, so expired before any date we target */
+                    DateTime::<Utc>::MIN_UTC
+                }))
+            } else {
+                None
+            }
+        })
+        .try_fold(DateTime::<Utc>::MIN_UTC, |latest, end| {
+            Some(latest.max(end?))
+        })
+}
+
+/// Date a claim refers to (P585), for values that change over time like the population
+pub(crate) fn claim_point_in_time(claim: &Claim) -> Option<DateTime<Utc>> {
+    let times = claim.qualifiers.as_ref()?.get(POINT_IN_TIME_CLAIM)?;
+    times.iter().find_map(|time| {
+        if let Snak::Time { value } = time {
+            parse_time(value).ok()
         } else {
-            false
+            None
         }
     })
 }
 
+fn parse_time(value: &Time) -> Result<DateTime<Utc>, String> {
+    //println!("'{}' (precision {})", value.time, value.precision);
+    let s = match value.precision {
+        0..=9 => &format!("{}-01-01T00:00:00Z", &value.time[..5]),
+        10 => &format!("{}-01T00:00:00Z", &value.time[..8]),
+        _ => value.time,
+    };
+    DateTime::parse_from_str(s, "%+")
+        .map(|dt| dt.to_utc())
+        .map_err(|e| {
+            format!(
+                "Cannot parse date '{}' of precision {}: {e}",
+                s, value.precision
+            )
+        })
+}
+
+/// Nature of the claim and its subject roles; none for an unknown nature or "no value"
 pub(crate) fn claim_and_roles(claim: &Claim) -> impl Iterator<Item = u64> {
     let main = if let Snak::Item { value } = &claim.mainsnak {
-        int_id_faillible(value.id).expect("Invalid nature id")
+        int_id_faillible(value.id)
+            .inspect_err(|e| println!("Warning: invalid nature claim: {e}"))
+            .ok()
     } else {
-        panic!("No nature id")
+        None
     };
-    [main]
-        .into_iter()
+    main.into_iter()
         .chain(claim.qualifiers.iter().flat_map(|quals| {
             quals
                 .get(SUBJECT_ROLE_CLAIM)
@@ -338,7 +815,7 @@ struct QualifiersIterator {
 }
 */
 
-fn _format<'a>(item: &Element<'a>) -> String {
+fn _format<'a>(item: &Element<'a>, as_of: DateTime<Utc>) -> String {
     format!(
         "{} ({}): {}",
         item.id,
@@ -350,7 +827,7 @@ fn _format<'a>(item: &Element<'a>) -> String {
                 format!(
                     "{}{}",
                     value.id,
-                    if !claim_still_valid(nat) {
+                    if !claim_still_valid(nat, as_of) {
                         "(obsolete)"
                     } else {
                         ""
@@ -372,17 +849,152 @@ fn _format<'a>(item: &Element<'a>) -> String {
 }
 
 #[expect(unused)]
-fn count<'a>(natures: &mut HashMap<String, u64>, item: &Element<'a>) {
+fn count<'a>(natures: &mut HashMap<String, u64>, item: &Element<'a>, as_of: DateTime<Utc>) {
     item.claims
         .get(NATURE_CLAIM)
         .unwrap_or(&vec![])
         .iter()
         .for_each(|nat| {
-            if let Snak::Item { value } = &nat.mainsnak {
-                if claim_still_valid(nat) {
-                    let nat = value.id.to_string();
-                    (*natures.entry(nat).or_insert(0)) += 1;
-                }
+            if let Snak::Item { value } = &nat.mainsnak
+                && claim_still_valid(nat, as_of)
+            {
+                let nat = value.id.to_string();
+                (*natures.entry(nat).or_insert(0)) += 1;
             }
         });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn date(y: i32, m: u32, d: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, 0, 0, 0)
+            .single()
+            .expect("valid date")
+    }
+
+    fn time(time: &str, precision: u8) -> serde_json::Value {
+        serde_json::json!({
+            "snaktype": "value",
+            "datatype": "time",
+            "datavalue": {"value": {"time": time, "precision": precision}, "type": "time"}
+        })
+    }
+
+    /// Without a datavalue, as in the dumps
+    fn no_time(snaktype: &str) -> serde_json::Value {
+        serde_json::json!({"snaktype": snaktype, "datatype": "time"})
+    }
+
+    fn interval(qualifiers: serde_json::Value) -> Interval {
+        let json = serde_json::json!({
+            "mainsnak": {"datatype": "wikibase-item", "datavalue": {"value": {"id": "Q1"}}},
+            "qualifiers": qualifiers
+        })
+        .to_string();
+        let claim: Claim = serde_json::from_str(&json).expect("valid claim");
+        claim_interval(&claim)
+    }
+
+    #[test]
+    fn time_precision() {
+        let parse = |time, precision| parse_time(&Time { time, precision }).expect("valid time");
+        // Truncated to the start of the year or month
+        assert_eq!(parse("+1800-00-00T00:00:00Z", 7), date(1800, 1, 1));
+        assert_eq!(parse("+2020-00-00T00:00:00Z", 9), date(2020, 1, 1));
+        assert_eq!(parse("+2020-05-00T00:00:00Z", 10), date(2020, 5, 1));
+        assert_eq!(parse("+2020-05-17T00:00:00Z", 11), date(2020, 5, 17));
+        assert!(
+            parse_time(&Time {
+                time: "+2020-13-45T00:00:00Z",
+                precision: 11
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn interval_bounds_are_inclusive() {
+        let interval = Interval {
+            start: Some(date(2000, 1, 1)),
+            end: Some(date(2010, 1, 1)),
+        };
+        let second = chrono::Duration::seconds(1);
+        assert!(interval.contains(date(2000, 1, 1)));
+        assert!(interval.contains(date(2010, 1, 1)));
+        assert!(!interval.contains(date(2000, 1, 1) - second));
+        assert!(!interval.contains(date(2010, 1, 1) + second));
+        assert!(Interval::default().contains(DateTime::<Utc>::MIN_UTC));
+        assert!(Interval::default().contains(DateTime::<Utc>::MAX_UTC));
+    }
+
+    #[test]
+    fn claim_intervals() {
+        assert_eq!(interval(serde_json::Value::Null), Interval::default());
+        // The earliest start, and the latest end
+        let several = interval(serde_json::json!({
+            "P580": [time("+2000-01-01T00:00:00Z", 11), time("+1990-00-00T00:00:00Z", 9)],
+            "P582": [time("+2020-00-00T00:00:00Z", 9), time("+2010-06-15T00:00:00Z", 11)]
+        }));
+        assert_eq!(
+            several,
+            Interval {
+                start: Some(date(1990, 1, 1)),
+                end: Some(date(2020, 1, 1)),
+            }
+        );
+        // An end year is truncated to its start: the claim no longer holds during that year
+        assert!(!several.contains(date(2020, 6, 1)));
+        // Unknown or no value: unbounded on that side, even next to a known one
+        for snaktype in ["somevalue", "novalue"] {
+            assert_eq!(
+                interval(serde_json::json!({
+                    "P580": [no_time(snaktype)],
+                    "P582": [time("+2010-06-15T00:00:00Z", 11), no_time(snaktype)]
+                })),
+                Interval::default()
+            );
+        }
+    }
+
+    #[test]
+    fn deprecated_statements_are_dropped() {
+        let item = |id: &str, rank: &str| {
+            serde_json::json!({
+                "mainsnak": {"datatype": "wikibase-item", "datavalue": {"value": {"id": id}}},
+                "rank": rank
+            })
+        };
+        let json = serde_json::json!({
+            "id": "Q1",
+            "labels": {},
+            "claims": {
+                "P31": [
+                    item("Q5", "deprecated"),
+                    item("Q484170", "preferred"),
+                    item("Q6", "normal")
+                ],
+                "P47": [item("Q2", "deprecated")]
+            }
+        })
+        .to_string();
+        let mut el: Element = serde_json::from_str(&json).expect("valid entity");
+        el.drop_deprecated();
+        let natures: Vec<(&str, Rank)> = el.claims[NATURE_CLAIM]
+            .iter()
+            .map(|claim| match &claim.mainsnak {
+                Snak::Item { value } => (value.id, claim.rank),
+                _ => panic!("not an item"),
+            })
+            .collect();
+        assert_eq!(
+            natures,
+            [("Q484170", Rank::Preferred), ("Q6", Rank::Normal)]
+        );
+        // Without statements left, the property is gone
+        assert!(!el.claims.contains_key(SHARES_BORDER_WITH_CLAIM));
+        assert_eq!(el.deprecated, [NATURE_CLAIM, SHARES_BORDER_WITH_CLAIM]);
+    }
+}
@@ -0,0 +1,216 @@
+// Ordered parallel processing
+//
+// Both the bzip2 decoder and the ingestion split their input in chunks, process them on a pool of
+// threads, and need the results back in input order. A chunk can only be sent once there is room
+// for it, that is once a result was consumed: results computed ahead of a slow chunk wait for
+// their turn, and memory stays bounded whatever the thread scheduling.
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Channels of a pool processing at most `window` chunks at a time, sent to the feeder, processed
+/// by the workers, and read back in order
+pub(crate) fn ordered<J, R>(window: usize) -> (Feeder<J>, Workers<J, R>, Ordered<R>) {
+    let (jobs_tx, jobs_rx) = mpsc::sync_channel(window);
+    let (results_tx, results_rx) = mpsc::sync_channel(window);
+    let (credits_tx, credits_rx) = mpsc::sync_channel(window);
+    for _ in 0..window {
+        credits_tx.send(()).expect("within capacity");
+    }
+    let sent = Arc::new(AtomicU64::new(0));
+    (
+        Feeder {
+            jobs: jobs_tx,
+            credits: credits_rx,
+            sent: Arc::clone(&sent),
+        },
+        Workers {
+            jobs: Arc::new(Mutex::new(jobs_rx)),
+            results: results_tx,
+        },
+        Ordered {
+            results: results_rx,
+            waiting: BTreeMap::new(),
+            next_seq: 0,
+            credited: 0,
+            credits: credits_tx,
+            sent,
+        },
+    )
+}
+
+/// Sends the chunks to process, in input order
+pub(crate) struct Feeder<J> {
+    jobs: SyncSender<(u64, J)>,
+    /// One per consumed result, giving room for another chunk
+    credits: Receiver<()>,
+    sent: Arc<AtomicU64>,
+}
+impl<J> Feeder<J> {
+    /// Wait for room and send a chunk; false when results are no longer read
+    pub(crate) fn send(&self, job: J) -> bool {
+        if self.credits.recv().is_err() {
+            return false;
+        }
+        let seq = self.sent.load(Ordering::Acquire);
+        if self.jobs.send((seq, job)).is_err() {
+            return false;
+        }
+        self.sent.store(seq + 1, Ordering::Release);
+        true
+    }
+}
+
+/// Hands out workers, to be run on threads spawned by the caller
+pub(crate) struct Workers<J, R> {
+    jobs: Arc<Mutex<Receiver<(u64, J)>>>,
+    results: SyncSender<(u64, thread::Result<R>)>,
+}
+impl<J, R> Workers<J, R> {
+    /// Create the workers; results are over once all of them are done
+    pub(crate) fn start(self, threads: usize, mut spawn: impl FnMut(Worker<J, R>)) {
+        for _ in 0..threads {
+            spawn(Worker {
+                jobs: Arc::clone(&self.jobs),
+                results: self.results.clone(),
+            });
+        }
+    }
+}
+
+pub(crate) struct Worker<J, R> {
+    jobs: Arc<Mutex<Receiver<(u64, J)>>>,
+    results: SyncSender<(u64, thread::Result<R>)>,
+}
+impl<J, R> Worker<J, R> {
+    /// Process chunks until there are none left, or results are no longer read
+    pub(crate) fn run(self, work: impl Fn(J) -> R) {
+        loop {
+            let (seq, job) = match self.jobs.lock().expect("poisoned").recv() {
+                Ok(job) => job,
+                Err(_) => break,
+            };
+            // A panic must not lose the chunk, or the reader would wait for it forever
+            let result = panic::catch_unwind(AssertUnwindSafe(|| work(job)));
+            if self.results.send((seq, result)).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Results, in the order their chunks were sent
+pub(crate) struct Ordered<R> {
+    results: Receiver<(u64, thread::Result<R>)>,
+    /// Results computed ahead of the next one
+    waiting: BTreeMap<u64, R>,
+    next_seq: u64,
+    /// Results consumed so far, each of them having given room for another chunk
+    credited: u64,
+    credits: SyncSender<()>,
+    sent: Arc<AtomicU64>,
+}
+impl<R> Ordered<R> {
+    /// The next result; None once the feeder is gone and every chunk it sent was returned
+    pub(crate) fn next(&mut self) -> Result<Option<R>, String> {
+        let seq = self.next_seq;
+        let result = loop {
+            if let Some(result) = self.waiting.remove(&seq) {
+                break result;
+            }
+            match self.results.recv() {
+                Ok((s, Ok(result))) => {
+                    self.waiting.insert(s, result);
+                }
+                Ok((s, Err(e))) => {
+                    return Err(format!("chunk {s} failed: {}", panic_message(&*e)));
+                }
+                // All workers are gone
+                Err(_) => {
+                    let sent = self.sent.load(Ordering::Acquire);
+                    if seq < sent || !self.waiting.is_empty() {
+                        return Err(format!("chunk {seq} of {sent} was lost"));
+                    }
+                    return Ok(None);
+                }
+            }
+        };
+        self.next_seq += 1;
+        if self.next_seq > self.credited {
+            self.credited = self.next_seq;
+            // The feeder may already be done
+            let _ = self.credits.send(());
+        }
+        Ok(Some(result))
+    }
+
+    /// Give back the last result, to be returned again by the next call
+    pub(crate) fn put_back(&mut self, result: R) {
+        self.next_seq -= 1;
+        self.waiting.insert(self.next_seq, result);
+    }
+}
+
+fn panic_message(e: &(dyn Any + Send)) -> &str {
+    e.downcast_ref::<&str>()
+        .copied()
+        .or_else(|| e.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("panicked")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Squares of 0..n, computed on 4 threads with a window of 3
+    fn squares(n: u64, work: fn(u64) -> u64) -> Ordered<u64> {
+        let (feeder, workers, ordered) = ordered(3);
+        workers.start(4, |worker| {
+            thread::spawn(move || worker.run(work));
+        });
+        thread::spawn(move || (0..n).all(|i| feeder.send(i)));
+        ordered
+    }
+
+    #[test]
+    fn in_order() {
+        // Later chunks are done first
+        let mut ordered = squares(50, |i| {
+            thread::sleep(Duration::from_millis((50 - i) % 7));
+            i * i
+        });
+        let results: Vec<u64> = std::iter::from_fn(|| ordered.next().expect("no panic")).collect();
+        assert_eq!(results, (0..50).map(|i| i * i).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn put_back() {
+        let mut ordered = squares(3, |i| i * i);
+        assert_eq!(ordered.next(), Ok(Some(0)));
+        let one = ordered.next().expect("no panic").expect("more");
+        ordered.put_back(one);
+        assert_eq!(ordered.next(), Ok(Some(1)));
+        assert_eq!(ordered.next(), Ok(Some(4)));
+        assert_eq!(ordered.next(), Ok(None));
+    }
+
+    #[test]
+    fn panic_is_an_error() {
+        // Many more chunks than the window: the feeder would wait forever for the lost one
+        let mut ordered = squares(100, |i| {
+            if i == 5 {
+                panic!("bad chunk");
+            }
+            i * i
+        });
+        for i in 0..5 {
+            assert_eq!(ordered.next(), Ok(Some(i * i)));
+        }
+        assert_eq!(ordered.next(), Err("chunk 5 failed: bad chunk".to_string()));
+    }
+}
@@ -0,0 +1,615 @@
+// Reports of the Wikidata statements that look wrong, for editors to fix them
+use crate::Config;
+use crate::cli::{Report, ReportFormat};
+use crate::db::{Statements, int_id_faillible, median};
+use crate::geojson::top_categories;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use indexmap::IndexMap;
+use serde::Serialize;
+
+/// Row of a report, written as JSON or CSV
+trait ReportRow: Serialize {
+    const COLUMNS: &[&str];
+    fn fields(&self) -> Vec<String>;
+}
+
+/// A border stated by `from` only
+#[derive(Serialize)]
+struct OneSidedBorder {
+    from: String,
+    from_label: String,
+    to: String,
+    to_label: String,
+}
+impl ReportRow for OneSidedBorder {
+    const COLUMNS: &[&str] = &["from", "from_label", "to", "to_label"];
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.from.clone(),
+            self.from_label.clone(),
+            self.to.clone(),
+            self.to_label.clone(),
+        ]
+    }
+}
+
+/// A border, and how it compares to the others of its category
+#[derive(Serialize)]
+struct BorderLength {
+    a: String,
+    a_label: String,
+    b: String,
+    b_label: String,
+    /// Great-circle distance between both places; unknown on unknown globes
+    length_km: Option<f64>,
+    /// To the median border of the category
+    ratio: f64,
+    distant: bool,
+}
+impl ReportRow for BorderLength {
+    const COLUMNS: &[&str] = &[
+        "a",
+        "a_label",
+        "b",
+        "b_label",
+        "length_km",
+        "ratio",
+        "distant",
+    ];
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.a.clone(),
+            self.a_label.clone(),
+            self.b.clone(),
+            self.b_label.clone(),
+            self.length_km.map_or(String::new(), |l| format!("{l:.1}")),
+            format!("{:.2}", self.ratio),
+            self.distant.to_string(),
+        ]
+    }
+}
+
+/// A place whose position looks wrong
+#[derive(Serialize)]
+struct PositionIssue {
+    id: String,
+    label: String,
+    lat: f64,
+    lon: f64,
+    issue: &'static str,
+    detail: String,
+}
+impl ReportRow for PositionIssue {
+    const COLUMNS: &[&str] = &["id", "label", "lat", "lon", "issue", "detail"];
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.id.clone(),
+            self.label.clone(),
+            self.lat.to_string(),
+            self.lon.to_string(),
+            self.issue.to_string(),
+            self.detail.clone(),
+        ]
+    }
+}
+
+pub(crate) fn report(
+    statements: &mut Statements,
+    config: &Config,
+    report: Report,
+    category: Option<u64>,
+    format: ReportFormat,
+    output: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    // With the globe of their places
+    let mut categories = vec![];
+    for globe in &config.globes {
+        let globe = int_id_faillible(globe).map_err(|e| format!("Invalid globe: {e}"))?;
+        match category {
+            Some(category) => categories.push((category, globe)),
+            None => categories.extend(
+                top_categories(statements, config, globe)?
+                    .into_iter()
+                    .map(|category| (category, globe)),
+            ),
+        }
+    }
+    match report {
+        Report::Asymmetric => {
+            let mut results = IndexMap::new();
+            for (category, _) in categories {
+                results.insert(
+                    format!("Q{category}"),
+                    asymmetric(statements, config, category)?,
+                );
+            }
+            write_report(&results, format, output)
+        }
+        Report::Quality => {
+            let issues = position_issues(statements)?;
+            let mut results = IndexMap::new();
+            for (category, globe) in categories {
+                let rows = quality(statements, config, &issues, category, globe)?;
+                results
+                    .entry(format!("Q{category}"))
+                    .or_insert_with(Vec::new)
+                    .extend(rows);
+            }
+            write_report(&results, format, output)
+        }
+        Report::Distance { all } => {
+            let mut results = IndexMap::new();
+            for (category, globe) in categories {
+                let mut borders = distances(statements, config, category, globe)?;
+                if !all {
+                    borders.retain(|border| border.distant);
+                }
+                results
+                    .entry(format!("Q{category}"))
+                    .or_insert_with(Vec::new)
+                    .extend(borders);
+            }
+            write_report(&results, format, output)
+        }
+    }
+}
+
+fn asymmetric(
+    statements: &mut Statements,
+    config: &Config,
+    category: u64,
+) -> Result<Vec<OneSidedBorder>, Box<dyn Error>> {
+    let mut rows = statements
+        .select_asymmetric_category
+        .query((category, config.as_of().timestamp()))?;
+    let mut borders = vec![];
+    while let Some(row) = rows.next()? {
+        borders.push(OneSidedBorder {
+            from: format!("Q{}", row.get::<_, u64>(0)?),
+            from_label: label(config, &row.get::<_, String>(2)?)?,
+            to: format!("Q{}", row.get::<_, u64>(1)?),
+            to_label: label(config, &row.get::<_, String>(3)?)?,
+        });
+    }
+    Ok(borders)
+}
+
+/// The borders of a category, longest first
+fn distances(
+    statements: &mut Statements,
+    config: &Config,
+    category: u64,
+    globe: u64,
+) -> Result<Vec<BorderLength>, Box<dyn Error>> {
+    let mut rows = statements.select_edges_category.query((
+        category,
+        config.as_of().timestamp(),
+        globe,
+        config.referenced_edges_only,
+        false,
+        false,
+        true,
+        false,
+    ))?;
+    let mut borders = vec![];
+    while let Some(row) = rows.next()? {
+        let (a, b): (u64, u64) = (row.get(8)?, row.get(9)?);
+        let angle = central_angle((row.get(1)?, row.get(0)?), (row.get(3)?, row.get(2)?));
+        borders.push((
+            a,
+            b,
+            row.get::<_, String>(10)?,
+            row.get::<_, String>(11)?,
+            angle,
+        ));
+    }
+    let mut angles: Vec<f64> = borders.iter().map(|border| border.4).collect();
+    if angles.is_empty() {
+        return Ok(vec![]);
+    }
+    let median = median(&mut angles);
+    let mut borders = borders
+        .into_iter()
+        .map(|(a, b, a_labels, b_labels, angle)| {
+            // Places at the same position have no length at all
+            let ratio = if median > 0.0 { angle / median } else { 0.0 };
+            Ok(BorderLength {
+                a: format!("Q{a}"),
+                a_label: label(config, &a_labels)?,
+                b: format!("Q{b}"),
+                b_label: label(config, &b_labels)?,
+                length_km: globe_radius_km(globe).map(|radius| angle * radius),
+                ratio,
+                distant: ratio > config.distant_edges_ratio,
+            })
+        })
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+    borders.sort_by(|x, y| y.ratio.total_cmp(&x.ratio));
+    Ok(borders)
+}
+
+/// Record the distant borders of a category, to exclude them from the generated ones
+pub(crate) fn store_distant_edges(
+    statements: &mut Statements,
+    config: &Config,
+    category: u64,
+    globe: u64,
+) -> Result<(), Box<dyn Error>> {
+    let borders = distances(statements, config, category, globe)?;
+    statements.delete_distant_edges.execute((category,))?;
+    for border in borders.iter().filter(|border| border.distant) {
+        statements.insert_distant_edge.execute((
+            category,
+            int_id_faillible(&border.a)?,
+            int_id_faillible(&border.b)?,
+        ))?;
+    }
+    Ok(())
+}
+
+/// Issue and detail, by place
+type Issues = BTreeMap<u64, Vec<(&'static str, String)>>;
+
+/// Places at (0, 0), whose latitude and longitude look swapped compared to their neighbours, or
+/// at the exact same position as other places while further from their own neighbours. Only
+/// places and neighbours that existed at the same time are compared.
+fn position_issues(statements: &mut Statements) -> Result<Issues, Box<dyn Error>> {
+    let mut issues = Issues::new();
+    let mut add = |id, issue, detail| issues.entry(id).or_default().push((issue, detail));
+    let null_island = statements
+        .select_null_island
+        .query_map((), |row| row.get::<_, u64>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    for id in null_island {
+        add(id, "null island", String::new());
+    }
+    let neighbours = statements
+        .select_neighbour_positions
+        .query_map((), |row| {
+            Ok((
+                row.get::<_, u64>(0)?,
+                (row.get::<_, f64>(1)?, row.get::<_, f64>(2)?),
+                (row.get::<_, f64>(3)?, row.get::<_, f64>(4)?),
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    // Median position of the neighbours of each place
+    let mut around = BTreeMap::new();
+    for rows in neighbours.chunk_by(|x, y| x.0 == y.0) {
+        let (mut lats, mut lons): (Vec<f64>, Vec<f64>) = rows.iter().map(|row| row.2).unzip();
+        around.insert(rows[0].0, (median(&mut lats), median(&mut lons)));
+    }
+    let duplicates = statements
+        .select_duplicate_positions
+        .query_map((), |row| {
+            Ok((
+                row.get::<_, u64>(0)?,
+                (row.get::<_, f64>(1)?, row.get::<_, f64>(2)?),
+                row.get::<_, String>(3)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    // Without neighbours, any of them could be wrong
+    let distance = |id: u64, position| {
+        around
+            .get(&id)
+            .map_or(f64::INFINITY, |&around| central_angle(position, around))
+    };
+    for (id, position, others) in duplicates {
+        let others = others
+            .split(' ')
+            .map(|other| other.parse::<u64>())
+            .collect::<Result<Vec<_>, _>>()?;
+        // The place closest to its neighbours is likely the one really there
+        let own = distance(id, position);
+        if own.is_finite() && others.iter().all(|&other| own <= distance(other, position)) {
+            continue;
+        }
+        let others: Vec<String> = others.iter().map(|other| format!("Q{other}")).collect();
+        add(id, "duplicate", format!("same as {}", others.join(" ")));
+    }
+    for rows in neighbours.chunk_by(|x, y| x.0 == y.0) {
+        let (id, (lat, lon), _) = rows[0];
+        // Swapped, it would not be a valid latitude
+        if lon.abs() > 90.0 {
+            continue;
+        }
+        let around = around[&id];
+        // Much closer to its neighbours once swapped
+        if central_angle((lon, lat), around) * 10.0 < central_angle((lat, lon), around) {
+            add(
+                id,
+                "swapped",
+                format!("neighbours around {:.4}, {:.4}", around.0, around.1),
+            );
+        }
+    }
+    Ok(issues)
+}
+
+/// The places of a category that have position issues
+fn quality(
+    statements: &mut Statements,
+    config: &Config,
+    issues: &Issues,
+    category: u64,
+    globe: u64,
+) -> Result<Vec<PositionIssue>, Box<dyn Error>> {
+    let mut rows = statements.select_entities_category.query((
+        category,
+        config.as_of().timestamp(),
+        globe,
+        false,
+        false,
+    ))?;
+    let mut places = vec![];
+    while let Some(row) = rows.next()? {
+        let id: u64 = row.get(0)?;
+        let Some(place_issues) = issues.get(&id) else {
+            continue;
+        };
+        for (issue, detail) in place_issues {
+            places.push(PositionIssue {
+                id: format!("Q{id}"),
+                label: label(config, &row.get::<_, String>(1)?)?,
+                lat: row.get(4)?,
+                lon: row.get(3)?,
+                issue,
+                detail: detail.clone(),
+            });
+        }
+    }
+    Ok(places)
+}
+
+/// Record the places with position issues, to exclude them from the generated ones
+pub(crate) fn store_bad_positions(statements: &mut Statements) -> Result<(), Box<dyn Error>> {
+    let issues = position_issues(statements)?;
+    statements.delete_bad_positions.execute(())?;
+    for id in issues.keys() {
+        statements.insert_bad_position.execute((id,))?;
+    }
+    println!("places with position issues: {}", issues.len());
+    Ok(())
+}
+
+/// Between two (latitude, longitude) in degrees, in radians, with the haversine formula
+fn central_angle(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (lat1, lat2) = (from.0.to_radians(), to.0.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (to.1 - from.1).to_radians();
+    let h = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * h.sqrt().min(1.0).asin()
+}
+
+/// Mean radius
+fn globe_radius_km(globe: u64) -> Option<f64> {
+    match globe {
+        // Earth
+        2 => Some(6371.0),
+        // Moon
+        405 => Some(1737.4),
+        // Mars
+        111 => Some(3389.5),
+        _ => None,
+    }
+}
+
+/// In the first configured language, or its fallbacks
+fn label(config: &Config, labels: &str) -> Result<String, Box<dyn Error>> {
+    let labels: BTreeMap<String, String> = serde_json::from_str(labels)?;
+    let lang = config.languages.first().map_or("en", String::as_str);
+    Ok(std::iter::once(lang)
+        .chain(config.label_fallbacks(lang).iter().map(String::as_str))
+        .find_map(|l| labels.get(l))
+        .cloned()
+        .unwrap_or_default())
+}
+
+/// Rows by category: as a JSON object, or CSV lines with the category first
+fn write_report<T: ReportRow>(
+    results: &IndexMap<String, Vec<T>>,
+    format: ReportFormat,
+    output: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let mut out: BufWriter<Box<dyn Write>> = BufWriter::new(match output {
+        Some(path) => Box::new(
+            File::create(path).map_err(|e| format!("Cannot create {}: {e}", path.display()))?,
+        ),
+        None => Box::new(std::io::stdout().lock()),
+    });
+    match format {
+        ReportFormat::Json => {
+            serde_json::to_writer(&mut out, results)?;
+            writeln!(out)?;
+        }
+        ReportFormat::Csv => {
+            writeln!(out, "category,{}", T::COLUMNS.join(","))?;
+            for (category, rows) in results {
+                for row in rows {
+                    let fields: Vec<String> = row.fields().iter().map(|f| csv_field(f)).collect();
+                    writeln!(out, "{category},{}", fields.join(","))?;
+                }
+            }
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use crate::process_line;
+
+    /// A place of nature Q484170 at (lat, lon), sharing a border with each of `borders`, with
+    /// other claims
+    fn place(
+        id: &str,
+        (lat, lon): (f64, f64),
+        borders: &[&str],
+        claims: serde_json::Value,
+    ) -> String {
+        let item = |id: &str| {
+            serde_json::json!({
+                "datatype": "wikibase-item",
+                "datavalue": {"value": {"id": id}}
+            })
+        };
+        let mut place = serde_json::json!({
+            "id": id,
+            "labels": {"en": {"value": format!("Place {id}")}},
+            "claims": {
+                "P31": [{"mainsnak": item("Q484170")}],
+                "P625": [{"mainsnak": {
+                    "datatype": "globe-coordinate",
+                    "datavalue": {"value": {"latitude": lat, "longitude": lon}}
+                }}],
+                "P47": borders.iter().map(|b| serde_json::json!({"mainsnak": item(b)})).collect::<Vec<_>>()
+            }
+        });
+        if let serde_json::Value::Object(claims) = claims {
+            place["claims"]
+                .as_object_mut()
+                .expect("claims")
+                .extend(claims);
+        }
+        place.to_string()
+    }
+
+    /// Claim of the given time property, e.g. P571 for the inception
+    fn time(property: &str, time: &str) -> serde_json::Value {
+        serde_json::json!({property: [{"mainsnak": {
+            "datatype": "time",
+            "datavalue": {"value": {"time": time, "precision": 9}}
+        }}]})
+    }
+
+    fn ingest(conn: &mut rusqlite::Connection, config: &Config, lines: &[String]) {
+        db::create_tables(conn, &config.banned_generic_categories).expect("tables");
+        let mut statements = Statements::new(conn);
+        for (i, line) in lines.iter().enumerate() {
+            let rows = process_line(config, i, line)
+                .expect("matching")
+                .expect("valid");
+            db::insert(&mut statements, &rows);
+        }
+    }
+
+    #[test]
+    fn duplicates_far_from_their_neighbours() {
+        let config = Config::default();
+        let mut conn = rusqlite::Connection::open_in_memory().expect("in memory DB");
+        let founded = || time("P571", "+1950-00-00T00:00:00Z");
+        ingest(
+            &mut conn,
+            &config,
+            &[
+                place("Q1", (45.0, 2.0), &["Q2"], founded()),
+                place("Q2", (45.1, 2.1), &["Q1"], serde_json::json!({})),
+                // At the position of Q1, far from its own neighbour
+                place("Q3", (45.0, 2.0), &["Q4"], founded()),
+                place("Q4", (10.0, 10.0), &["Q3"], serde_json::json!({})),
+                // Also there, but dissolved before the others existed
+                place(
+                    "Q5",
+                    (45.0, 2.0),
+                    &["Q4"],
+                    time("P576", "+1900-00-00T00:00:00Z"),
+                ),
+            ],
+        );
+        let mut statements = Statements::new(&conn);
+        let issues = position_issues(&mut statements).expect("issues");
+        assert_eq!(
+            issues.into_iter().collect::<Vec<_>>(),
+            [(3, vec![("duplicate", "same as Q1".to_string())])]
+        );
+    }
+
+    #[test]
+    fn one_sided_borders() {
+        let config = Config::default();
+        let mut conn = rusqlite::Connection::open_in_memory().expect("in memory DB");
+        let none = || serde_json::json!({});
+        ingest(
+            &mut conn,
+            &config,
+            &[
+                // Only stated by Q1
+                place("Q1", (45.0, 2.0), &["Q2"], none()),
+                // Stated by both
+                place("Q2", (45.1, 2.1), &["Q3"], none()),
+                place("Q3", (45.2, 2.2), &["Q2"], none()),
+            ],
+        );
+        let mut statements = Statements::new(&conn);
+        let borders = asymmetric(&mut statements, &config, 484170).expect("report");
+        assert_eq!(
+            borders
+                .iter()
+                .map(|b| (&*b.from, &*b.from_label, &*b.to, &*b.to_label))
+                .collect::<Vec<_>>(),
+            [("Q1", "Place Q1", "Q2", "Place Q2")]
+        );
+    }
+
+    #[test]
+    fn distant_borders() {
+        assert_eq!(median(&mut [3.0, 1.0, 2.0]), 2.0);
+        assert_eq!(median(&mut [4.0, 1.0, 3.0, 2.0]), 2.5);
+
+        let config = Config::default();
+        let mut conn = rusqlite::Connection::open_in_memory().expect("in memory DB");
+        let none = || serde_json::json!({});
+        ingest(
+            &mut conn,
+            &config,
+            &[
+                place("Q1", (45.0, 2.0), &["Q2"], none()),
+                place("Q2", (45.0, 2.1), &["Q3"], none()),
+                place("Q3", (45.0, 2.2), &["Q4"], none()),
+                place("Q4", (45.0, 2.3), &["Q5"], none()),
+                // Far from the others
+                place("Q5", (50.0, 10.0), &["Q4"], none()),
+            ],
+        );
+        let mut statements = Statements::new(&conn);
+        let borders = distances(&mut statements, &config, 484170, 2).expect("report");
+        // Longest first
+        assert_eq!(
+            borders
+                .iter()
+                .map(|b| (&*b.a, &*b.b, b.distant))
+                .collect::<Vec<_>>(),
+            [
+                ("Q4", "Q5", true),
+                ("Q1", "Q2", false),
+                ("Q2", "Q3", false),
+                ("Q3", "Q4", false),
+            ]
+        );
+        assert!(borders[0].ratio > config.distant_edges_ratio);
+        // Each about 7.9 km long, the median
+        for border in &borders[1..] {
+            assert!((border.ratio - 1.0).abs() < 0.01, "{}", border.ratio);
+            let length = border.length_km.expect("on Earth");
+            assert!((length - 7.9).abs() < 0.1, "{length}");
+        }
+    }
+}
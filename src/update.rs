@@ -0,0 +1,476 @@
+use super::Config;
+use super::db;
+use super::dump;
+use super::process_line;
+
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use quick_xml::events::Event;
+use serde::Deserialize;
+use serde::de::IgnoredAny;
+
+/// Just enough of an entity to know which one it is, and whether it still exists
+#[derive(Deserialize)]
+struct EntityStub<'a> {
+    id: &'a str,
+    /// Set instead of the content by the wbgetentities API (and Special:EntityData) for deleted
+    /// entities
+    missing: Option<IgnoredAny>,
+    /// Set by the wbgetentities API when the requested entity was merged into this one
+    redirects: Option<Redirect<'a>>,
+}
+
+#[derive(Deserialize)]
+struct Redirect<'a> {
+    from: &'a str,
+}
+
+#[derive(Default)]
+struct UpdateStats {
+    updated: u64,
+    deleted: u64,
+}
+
+/// Apply newer versions of entities to an existing intermediate DB
+///
+/// The source is either a file in the same format as the full dump (an entity per line), a daily
+/// incremental dump (pages-meta-hist-incr.xml), or a directory of entity JSON files, as returned
+/// by Special:EntityData or the wbgetentities API.
+pub(crate) fn update_db(
+    config: &Config,
+    conn: &rusqlite::Connection,
+    statements: &mut db::Statements,
+    source: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut stats = UpdateStats::default();
+    db::begin(conn)?;
+    if Path::new(source).is_dir() {
+        update_from_dir(config, statements, source, &mut stats)?;
+    } else {
+        update_from_dump(config, statements, source, &mut stats)?;
+    }
+    // Neighbours may have moved too
+    db::pick_positions(conn, config.as_of())?;
+    // Generate files as of the update by default, not to miss the claims it brought
+    db::set_metadata(conn, "as_of", config.as_of().date_naive().to_string())?;
+    db::commit(conn)?;
+    println!(
+        "Updated {} entities, deleted {}",
+        stats.updated, stats.deleted
+    );
+    Ok(())
+}
+
+fn update_from_dump(
+    config: &Config,
+    statements: &mut db::Statements,
+    source: &str,
+    stats: &mut UpdateStats,
+) -> Result<(), Box<dyn Error>> {
+    let mut input = BufReader::with_capacity(64 * 1024, dump::open(source, 0)?);
+    let first = input
+        .fill_buf()
+        .map_err(|e| format!("Failed to read {source}: {e}"))?
+        .iter()
+        .find(|b| !b.is_ascii_whitespace());
+    if first == Some(&b'<') {
+        return update_from_xml(config, statements, source, input, stats);
+    }
+    for (i, line) in input.lines().enumerate() {
+        let l = line.map_err(|e| format!("Failed to read {source} at line {i}: {e}"))?;
+        // Skip empty first and last line
+        if l.len() <= 2 {
+            continue;
+        }
+        update_entity(
+            config,
+            statements,
+            i,
+            l.strip_suffix(',').unwrap_or(&l),
+            None,
+            stats,
+        )?;
+    }
+    Ok(())
+}
+
+/// Page of an XML dump, with the content of its last revision
+#[derive(Default)]
+struct Page {
+    title: String,
+    ns: String,
+    redirect: bool,
+    model: String,
+    /// Still escaped; missing when the revision was deleted
+    text: Option<String>,
+}
+
+/// Apply the incremental dumps of the MediaWiki pages of the entities, which have every revision
+/// of the day of the changed pages. Deleted pages are not in these dumps.
+fn update_from_xml<R: BufRead>(
+    config: &Config,
+    statements: &mut db::Statements,
+    source: &str,
+    input: R,
+    stats: &mut UpdateStats,
+) -> Result<(), Box<dyn Error>> {
+    let mut reader = quick_xml::Reader::from_reader(input);
+    let mut buf = Vec::with_capacity(64 * 1024);
+    let mut page = Page::default();
+    // Element whose text is being read
+    let mut element = String::new();
+    let mut pages = 0;
+    loop {
+        let event = reader.read_event_into(&mut buf).map_err(|e| {
+            format!(
+                "Invalid XML in {source} at byte {}: {e}",
+                reader.buffer_position()
+            )
+        })?;
+        match event {
+            Event::Start(e) => {
+                element = e.name().into_inner().to_string();
+                match element.as_str() {
+                    "page" => page = Page::default(),
+                    // Only the last revision matters
+                    "revision" => {
+                        page.model.clear();
+                        page.text = None;
+                    }
+                    "text" => page.text = Some(String::new()),
+                    _ => {}
+                }
+            }
+            Event::Empty(e) => match e.name().into_inner() {
+                "redirect" => page.redirect = true,
+                "text" => page.text = None,
+                _ => {}
+            },
+            Event::Text(e) => {
+                let text = &*e;
+                match element.as_str() {
+                    "title" => page.title.push_str(text),
+                    "ns" => page.ns.push_str(text),
+                    "model" => page.model.push_str(text),
+                    "text" => page.text.iter_mut().for_each(|t| t.push_str(text)),
+                    _ => {}
+                }
+            }
+            // Kept escaped in the text, to unescape it once
+            Event::GeneralRef(e) if element == "text" => {
+                if let Some(text) = &mut page.text {
+                    text.push('&');
+                    text.push_str(&e);
+                    text.push(';');
+                }
+            }
+            Event::End(e) => {
+                element.clear();
+                if e.name().into_inner() == "page" {
+                    apply_page(config, statements, pages, &page, stats)?;
+                    pages += 1;
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(())
+}
+
+fn apply_page(
+    config: &Config,
+    statements: &mut db::Statements,
+    i: usize,
+    page: &Page,
+    stats: &mut UpdateStats,
+) -> Result<(), Box<dyn Error>> {
+    // Only items are ingested, ignore properties, lexemes, and other pages
+    if page.ns != "0" || page.model != "wikibase-item" {
+        return Ok(());
+    }
+    // Merged into another entity
+    if page.redirect {
+        if let Ok(id) = db::int_id_faillible(&page.title) {
+            db::delete(statements, id);
+            stats.deleted += 1;
+        }
+        return Ok(());
+    }
+    let Some(text) = &page.text else {
+        return Ok(());
+    };
+    let json = quick_xml::escape::unescape(text)
+        .map_err(|e| format!("Invalid text of {}: {e}", page.title))?;
+    update_entity(config, statements, i, &json, None, stats)
+}
+
+fn update_from_dir(
+    config: &Config,
+    statements: &mut db::Statements,
+    source: &str,
+    stats: &mut UpdateStats,
+) -> Result<(), Box<dyn Error>> {
+    let mut files = fs::read_dir(source)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    files.retain(|f| f.extension().is_some_and(|ext| ext == "json"));
+    // Apply in a stable order
+    files.sort();
+    for (i, file) in files.iter().enumerate() {
+        let content =
+            fs::read_to_string(file).map_err(|e| format!("Cannot read {}: {e}", file.display()))?;
+        let json: serde_json::Value = serde_json::from_str(&content)
+            .map_err(|e| format!("Invalid JSON in {}: {e}", file.display()))?;
+        // Special:EntityData wraps entities in an object indexed by id
+        let entities = match json.get("entities") {
+            Some(serde_json::Value::Object(entities)) => entities.values().collect(),
+            _ => vec![&json],
+        };
+        // Special:EntityData/Q1.json returns the entity Q1 was merged into, when it's a redirect
+        let requested = file
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .filter(|_| entities.len() == 1);
+        for entity in entities {
+            update_entity(config, statements, i, &entity.to_string(), requested, stats)?;
+        }
+    }
+    Ok(())
+}
+
+fn update_entity(
+    config: &Config,
+    statements: &mut db::Statements,
+    i: usize,
+    json: &str,
+    requested: Option<&str>,
+    stats: &mut UpdateStats,
+) -> Result<(), Box<dyn Error>> {
+    let stub: EntityStub = serde_json::from_str(json)?;
+    // A merged entity is a redirect to the one it was merged into: it no longer exists
+    let merged = stub
+        .redirects
+        .as_ref()
+        .map(|r| r.from)
+        .into_iter()
+        .chain(requested)
+        .filter(|&from| from != stub.id)
+        .filter_map(|from| db::int_id_faillible(from).ok())
+        .collect::<BTreeSet<_>>();
+    for from in merged {
+        db::delete(statements, from);
+        stats.deleted += 1;
+    }
+    // Only items are ingested, ignore properties and lexemes
+    let Ok(id) = db::int_id_faillible(stub.id) else {
+        return Ok(());
+    };
+    db::delete(statements, id);
+    if stub.missing.is_some() {
+        stats.deleted += 1;
+        return Ok(());
+    }
+    // Entities that no longer match are only deleted
+    match process_line(config, i, json) {
+        Some(Ok(mut rows)) => {
+            rows.skip_deprecated_count();
+            db::insert(statements, &rows);
+        }
+        Some(Err(e)) => println!("{e}"),
+        None => {}
+    }
+    stats.updated += 1;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A place of nature Q484170, sharing a border with each of `borders`
+    fn place(id: &str, borders: &[&str]) -> String {
+        let item = |id: &str| {
+            serde_json::json!({
+                "datatype": "wikibase-item",
+                "datavalue": {"value": {"id": id}}
+            })
+        };
+        serde_json::json!({
+            "id": id,
+            "labels": {"en": {"value": format!("Place {id}")}},
+            "claims": {
+                "P31": [{"mainsnak": item("Q484170")}],
+                "P625": [{"mainsnak": {
+                    "datatype": "globe-coordinate",
+                    "datavalue": {"value": {"latitude": 45.0, "longitude": 2.0}}
+                }}],
+                "P47": borders.iter().map(|b| serde_json::json!({"mainsnak": item(b)})).collect::<Vec<_>>()
+            }
+        })
+        .to_string()
+    }
+
+    fn ingest(conn: &mut rusqlite::Connection, config: &Config, lines: &[String]) {
+        db::create_tables(conn, &config.banned_generic_categories).expect("tables");
+        let mut statements = db::Statements::new(conn);
+        for (i, line) in lines.iter().enumerate() {
+            let rows = process_line(config, i, line)
+                .expect("matching")
+                .expect("valid");
+            db::insert(&mut statements, &rows);
+        }
+    }
+
+    fn select(conn: &rusqlite::Connection, sql: &str) -> Vec<Vec<u64>> {
+        let mut st = conn.prepare(sql).expect("valid query");
+        st.query_map((), |row| {
+            (0..row.as_ref().column_count())
+                .map(|i| row.get(i))
+                .collect()
+        })
+        .expect("query")
+        .collect::<Result<_, _>>()
+        .expect("rows")
+    }
+
+    #[test]
+    fn borders_stated_by_neighbours_are_kept() {
+        let config = Config::default();
+        let mut conn = rusqlite::Connection::open_in_memory().expect("in memory DB");
+        ingest(
+            &mut conn,
+            &config,
+            &[
+                place("Q1", &["Q2"]),
+                place("Q2", &["Q1", "Q3"]),
+                place("Q3", &["Q2"]),
+            ],
+        );
+        let mut statements = db::Statements::new(&conn);
+        let mut stats = UpdateStats::default();
+        // Q1 now borders Q3 instead of Q2, which still states their border
+        update_entity(
+            &config,
+            &mut statements,
+            0,
+            &place("Q1", &["Q3"]),
+            None,
+            &mut stats,
+        )
+        .expect("valid update");
+        assert_eq!(
+            select(&conn, "SELECT a, b FROM edges ORDER BY a, b;"),
+            [[1, 2], [1, 3], [2, 3]]
+        );
+        assert_eq!(
+            select(
+                &conn,
+                "SELECT a, b, source FROM edge_qualifiers ORDER BY a, b, source;"
+            ),
+            [[1, 2, 2], [1, 3, 1], [2, 3, 2], [2, 3, 3]]
+        );
+    }
+
+    #[test]
+    fn merged_entities_are_deleted() {
+        let config = Config::default();
+        let mut conn = rusqlite::Connection::open_in_memory().expect("in memory DB");
+        ingest(
+            &mut conn,
+            &config,
+            &[
+                place("Q1", &["Q2"]),
+                place("Q2", &["Q1", "Q3"]),
+                place("Q3", &["Q2"]),
+            ],
+        );
+        let mut statements = db::Statements::new(&conn);
+        let mut stats = UpdateStats::default();
+        // As returned by Special:EntityData/Q3.json, once Q3 is merged into Q1
+        update_entity(
+            &config,
+            &mut statements,
+            0,
+            &place("Q1", &["Q2"]),
+            Some("Q3"),
+            &mut stats,
+        )
+        .expect("valid update");
+        assert_eq!((stats.updated, stats.deleted), (1, 1));
+        assert_eq!(
+            select(&conn, "SELECT id FROM entities ORDER BY id;"),
+            [[1], [2]]
+        );
+        assert_eq!(
+            select(
+                &conn,
+                "SELECT a, b, source FROM edge_qualifiers ORDER BY a, b, source;"
+            ),
+            [[1, 2, 1], [1, 2, 2], [2, 3, 2]]
+        );
+    }
+
+    #[test]
+    fn incremental_xml_dump() {
+        let config = Config::default();
+        let mut conn = rusqlite::Connection::open_in_memory().expect("in memory DB");
+        ingest(
+            &mut conn,
+            &config,
+            &[
+                place("Q1", &["Q2"]),
+                place("Q2", &["Q1", "Q3"]),
+                place("Q3", &["Q2"]),
+            ],
+        );
+        let revision = |json: &str| {
+            format!(
+                "<revision><id>1</id><model>wikibase-item</model>\
+                 <format>application/json</format>\
+                 <text bytes=\"{}\" xml:space=\"preserve\">{}</text></revision>",
+                json.len(),
+                quick_xml::escape::escape(json)
+            )
+        };
+        let xml = format!(
+            "<mediawiki><siteinfo><sitename>Wikidata</sitename></siteinfo>\n\
+             <page><title>Q1</title><ns>0</ns><id>1</id>{}{}</page>\n\
+             <page><title>Q3</title><ns>0</ns><id>3</id><redirect title=\"Q1\" />{}</page>\n\
+             <page><title>Property:P47</title><ns>120</ns><id>4</id>\
+             <revision><model>wikibase-property</model><text>{{}}</text></revision></page>\n\
+             </mediawiki>\n",
+            // Only the last revision of the day is applied
+            revision(&place("Q1", &["Q3"])),
+            revision(&place("Q1", &["Q2"])),
+            revision(r#"{"entity":"Q3","redirect":"Q1"}"#),
+        );
+        let mut statements = db::Statements::new(&conn);
+        let mut stats = UpdateStats::default();
+        update_from_xml(
+            &config,
+            &mut statements,
+            "incr.xml",
+            xml.as_bytes(),
+            &mut stats,
+        )
+        .expect("valid update");
+        assert_eq!((stats.updated, stats.deleted), (1, 1));
+        assert_eq!(
+            select(&conn, "SELECT id FROM entities ORDER BY id;"),
+            [[1], [2]]
+        );
+        assert_eq!(
+            select(
+                &conn,
+                "SELECT a, b, source FROM edge_qualifiers ORDER BY a, b, source;"
+            ),
+            [[1, 2, 1], [1, 2, 2], [2, 3, 2]]
+        );
+    }
+}
@@ -110,8 +110,8 @@ async function process() {
 	map.addSource('places_links', {
 		'type': 'geojson',
 		'data': {
-			'type': 'MultiLineString',
-			'coordinates': [],
+			'type': 'FeatureCollection',
+			'features': [],
 		},
 	});
 	map.addLayer({
@@ -176,6 +176,34 @@ async function process() {
 		map.setFilter('places', filter);
 		map.setFilter('labels', filter);
 	}
+	map.on('click', 'places', (e) => {
+		const props = e.features[0].properties;
+		const name = props[detectedLanguage] || props["en"];
+		// Labels and titles come from Wikidata: never interpret them as HTML
+		const link = (href, text) => {
+			const a = document.createElement('a');
+			a.href = href;
+			a.target = '_blank';
+			a.textContent = text;
+			return a;
+		};
+		const content = document.createElement('div');
+		content.appendChild(link('https://www.wikidata.org/wiki/' + encodeURIComponent(props.qid), name || props.qid));
+		const wiki = props[detectedLanguage + "wiki"] ? detectedLanguage : "en";
+		if (props[wiki + "wiki"]) {
+			content.appendChild(document.createElement('br'));
+			content.appendChild(link('https://' + wiki + '.wikipedia.org/wiki/' + encodeURIComponent(props[wiki + "wiki"]), 'Wikipedia'));
+		}
+		new maplibregl.Popup().setLngLat(e.lngLat).setDOMContent(content).addTo(map);
+	});
+	// Borders generated with edge_features know the places they connect
+	map.on('click', 'links', (e) => {
+		const props = e.features[0].properties;
+		if (!props.a)
+			return;
+		const name = (end) => props[end + "_" + detectedLanguage] || props[end + "_en"] || props[end];
+		new maplibregl.Popup().setLngLat(e.lngLat).setText(name("a") + " – " + name("b")).addTo(map);
+	});
 	filterInput.addEventListener('keyup', (e) => {
 		//const value = e.target.value.trim().toLowerCase();
 		updateFilter(e.target.value);
@@ -195,11 +223,13 @@ async function process() {
 async function initSelection() {
 	const select = document.getElementById("category");
 	Object.entries(index)
-		.map(([key, value]) => [key, value[detectedLanguage] || value["en"]])
+		.map(([key, value]) => [key, value[detectedLanguage] || value["en"], value.descriptions])
 		.sort(([,a],[,b]) => a.toLowerCase().localeCompare(b.toLowerCase()))
-		.forEach(([key, text]) => {
+		.forEach(([key, text, descriptions]) => {
 			var option = document.createElement("option");
-			option.text = text;
+			// Many categories share the same short name, like "district"
+			const description = descriptions && (descriptions[detectedLanguage] || descriptions["en"]);
+			option.text = description ? text + " (" + description + ")" : text;
 			option.value = key;
 			select.add(option);
 		})
@@ -211,13 +241,16 @@ async function initSelection() {
 	});
 }
 function getLanguage() {
-	const langList = navigator.languages || ["en"];
+	const langList = (navigator.languages || ["en"]).map((l) => l.toLowerCase());
+	// Full tags like pt-br or zh-hant have their own label fallbacks: prefer them when generated
+	const generated = new Set(Object.values(index).flatMap(Object.keys));
+	// Names in other languages fall back to English
 	return langList
-		.map((l) => l.split("-")[0])
-		.filter((l) => ["en", "fr"].includes(l))
-		[0] || "en";
+		.flatMap((l) => [l, l.split("-")[0]])
+		.find((l) => generated.has(l))
+		|| (langList[0] || "en").split("-")[0];
 }
-const detectedLanguage = getLanguage();
+var detectedLanguage;
 async function onSelect() {
 	const select = document.getElementById("category");
 	select.addEventListener("change", (event) => {
@@ -231,11 +264,13 @@ async function onSelect() {
 		select.dispatchEvent(new Event("change"));
 	}
 }
+// The language is picked among the ones of the index
+const indexLoaded = getIndex().then(() => detectedLanguage = getLanguage());
 Promise.all(
 	[
 	Promise.all([
-		load.then(process),
+		Promise.all([load, indexLoaded]).then(process),
 		getBgLayer(),
 		]).then(loadBgLayer),
-	getIndex().then(initSelection),
+	indexLoaded.then(initSelection),
 	]).then(onSelect);